@@ -0,0 +1,97 @@
+//! Delays that are either fixed or drawn at random from a range.
+//!
+//! `keydelay 2-10` waits a different time after every event, which reads as
+//! less mechanical than a constant. The generator is a small xorshift so runs
+//! can be repeated with `--seed`; it is not meant for anything else.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delay {
+    min: Duration,
+    max: Duration,
+}
+
+impl Delay {
+    pub fn fixed(delay: Duration) -> Self {
+        Delay {
+            min: delay,
+            max: delay,
+        }
+    }
+
+    /// Parses milliseconds, either `N` or `MIN-MAX`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.split_once('-') {
+            Some((min, max)) => {
+                let (min, max) = (millis(min)?, millis(max)?);
+                (min <= max).then_some(Delay { min, max })
+            }
+            None => millis(s).map(Delay::fixed),
+        }
+    }
+
+    pub fn is_fixed(self) -> bool {
+        self.min == self.max
+    }
+
+    pub fn sample(self, rng: &mut Rng) -> Duration {
+        if self.is_fixed() {
+            return self.min;
+        }
+        let span = (self.max - self.min).as_nanos() as u64;
+        self.min + Duration::from_nanos(rng.next_u64() % (span + 1))
+    }
+}
+
+impl fmt::Display for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_fixed() {
+            write!(f, "{}", format_millis(self.min))
+        } else {
+            write!(f, "{}-{}", format_millis(self.min), format_millis(self.max))
+        }
+    }
+}
+
+/// Parses a number of milliseconds, which may have a fraction, as in `0.5`.
+/// Negative, infinite and NaN values are refused.
+pub fn millis(s: &str) -> Option<Duration> {
+    let ms = s.trim().parse::<f64>().ok()?;
+    Duration::try_from_secs_f64(ms / 1000.0).ok()
+}
+
+/// Writes a duration in milliseconds the way `millis` reads it, with a
+/// fraction only when there is one.
+pub fn format_millis(d: Duration) -> String {
+    (d.as_nanos() as f64 / 1e6).to_string()
+}
+
+/// A seed from the clock, for when no `--seed` is given.
+pub fn seed_from_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// xorshift64*.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        const MIX: u64 = 0x9e37_79b9_7f4a_7c15;
+        // The state must never be zero.
+        match seed ^ MIX {
+            0 => Rng(MIX),
+            state => Rng(state),
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
@@ -0,0 +1,68 @@
+//! The difference between two event lists, for `--compare`.
+//!
+//! This is a plain longest common subsequence. The lists are trimmed to the
+//! part between their common start and end first, which for two versions of
+//! one script is usually short; if what is left is still too large for the
+//! table, all of it is reported as changed.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edit {
+    /// Only in the first list, at this index.
+    Removed(usize),
+    /// Only in the second list, at this index.
+    Added(usize),
+}
+
+/// The most cells the table may have.
+const MAX_CELLS: usize = 4_000_000;
+
+/// The edits that turn `a` into `b`, in order.
+pub fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let a = &a[prefix..a.len() - suffix];
+    let b = &b[prefix..b.len() - suffix];
+    let (n, m) = (a.len(), b.len());
+
+    if (n + 1) * (m + 1) > MAX_CELLS {
+        return (prefix..prefix + n)
+            .map(Edit::Removed)
+            .chain((prefix..prefix + m).map(Edit::Added))
+            .collect();
+    }
+
+    // lcs[at(i, j)] is the length of the longest common subsequence of a[i..]
+    // and b[j..].
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[at(i, j)] = if a[i] == b[j] {
+                lcs[at(i + 1, j + 1)] + 1
+            } else {
+                lcs[at(i + 1, j)].max(lcs[at(i, j + 1)])
+            };
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[at(i + 1, j)] >= lcs[at(i, j + 1)]) {
+            edits.push(Edit::Removed(prefix + i));
+            i += 1;
+        } else {
+            edits.push(Edit::Added(prefix + j));
+            j += 1;
+        }
+    }
+    edits
+}
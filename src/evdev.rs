@@ -0,0 +1,311 @@
+//! Reading state back from evdev devices under /dev/input.
+//!
+//! uinput devices are write-only, so anything that needs to know the state of
+//! the system (such as the lock LEDs) asks a real input device instead. This
+//! needs read access to it, which usually means being in the `input` group.
+//!
+//! Writing to an event node injects events as if that device sent them, which
+//! is how --use-device drives a device that already exists.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{mem, ptr, slice, thread};
+
+const EV_KEY: u32 = 0x01;
+const EV_LED: u32 = 0x11;
+const KEY_MAX: usize = 0x2ff;
+const LED_MAX: usize = 0x0f;
+
+/// Equivalent of the kernel's `_IOC(_IOC_READ, 'E', nr, len)`.
+fn eviocg(nr: u32, len: usize) -> libc::c_ulong {
+    ((2 << 30) | ((len as u32) << 16) | ((b'E' as u32) << 8) | nr) as libc::c_ulong
+}
+
+fn ioctl_bits(file: &File, nr: u32, max: usize) -> io::Result<Vec<u8>> {
+    let mut bits = vec![0u8; max / 8 + 1];
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), eviocg(nr, bits.len()), bits.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(bits)
+}
+
+fn bit_set(bits: &[u8], bit: usize) -> bool {
+    bits[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Led {
+    Num,
+    Caps,
+    Scroll,
+}
+
+impl Led {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "num" => Ok(Led::Num),
+            "caps" => Ok(Led::Caps),
+            "scroll" => Ok(Led::Scroll),
+            _ => Err(format!("unknown LED: {}", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Led::Num => "num",
+            Led::Caps => "caps",
+            Led::Scroll => "scroll",
+        }
+    }
+
+    fn code(self) -> usize {
+        match self {
+            Led::Num => 0,
+            Led::Caps => 1,
+            Led::Scroll => 2,
+        }
+    }
+}
+
+/// Whether `led` is lit on the device at `path`.
+pub fn led_on(path: &Path, led: Led) -> io::Result<bool> {
+    let file = File::open(path)?;
+    // EVIOCGLED
+    let state = ioctl_bits(&file, 0x19, LED_MAX)?;
+    Ok(bit_set(&state, led.code()))
+}
+
+/// The /dev/input event nodes of the devices called `name`.
+pub fn nodes_named(name: &str) -> io::Result<Vec<PathBuf>> {
+    let mut nodes = Vec::new();
+    for entry in fs::read_dir("/sys/class/input")? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(event) = file_name.to_str().filter(|n| n.starts_with("event")) else {
+            continue;
+        };
+        let Ok(device_name) = fs::read_to_string(entry.path().join("device/name")) else {
+            continue;
+        };
+        let node = Path::new("/dev/input").join(event);
+        if device_name.trim_end() == name && node.exists() {
+            nodes.push(node);
+        }
+    }
+    nodes.sort();
+    Ok(nodes)
+}
+
+/// Those of `codes` that the device at `path` did not declare as keys.
+pub fn missing_keys(path: &Path, codes: impl Iterator<Item = u16>) -> io::Result<Vec<u16>> {
+    let file = File::open(path)?;
+    // EVIOCGBIT(EV_KEY)
+    let keys = ioctl_bits(&file, 0x20 + EV_KEY, KEY_MAX)?;
+    Ok(codes
+        .filter(|&code| code as usize > KEY_MAX || !bit_set(&keys, code as usize))
+        .collect())
+}
+
+/// The first device under /dev/input that has `led`.
+pub fn find_led_device(led: Led) -> io::Result<PathBuf> {
+    for path in event_nodes()? {
+        let Ok(file) = File::open(&path) else {
+            continue;
+        };
+        // EVIOCGBIT(EV_LED)
+        if let Ok(bits) = ioctl_bits(&file, 0x20 + EV_LED, LED_MAX)
+            && bit_set(&bits, led.code())
+        {
+            return Ok(path);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no readable input device has a {} lock LED", led.name()),
+    ))
+}
+
+/// The event nodes under /dev/input, in order.
+fn event_nodes() -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir("/dev/input")?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("event"))
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// A device held with EVIOCGRAB, so that its events reach nothing else until
+/// it is dropped.
+struct Grabbed(File);
+
+impl Grabbed {
+    fn new(file: File) -> Self {
+        // EVIOCGRAB. Another program may hold the grab already, in which case
+        // the device is only read.
+        unsafe { libc::ioctl(file.as_raw_fd(), 0x4004_4590, 1) };
+        Grabbed(file)
+    }
+}
+
+impl Drop for Grabbed {
+    fn drop(&mut self) {
+        unsafe { libc::ioctl(self.0.as_raw_fd(), 0x4004_4590, 0) };
+    }
+}
+
+/// Whether any key is down on the device.
+fn keys_down(file: &File) -> bool {
+    // EVIOCGKEY
+    ioctl_bits(file, 0x18, KEY_MAX).is_ok_and(|state| state.iter().any(|&byte| byte != 0))
+}
+
+/// Waits up to `timeout` for the key with `code` to be pressed on any
+/// readable device that has it, other than those in `skip`. The devices are
+/// grabbed while waiting, so the key press, and anything else typed in the
+/// meantime, reaches no application. Whether the key was pressed in time.
+pub fn wait_for_key(code: u16, timeout: Duration, skip: &[PathBuf]) -> io::Result<bool> {
+    let mut files = Vec::new();
+    for path in event_nodes()? {
+        if skip.contains(&path) {
+            continue;
+        }
+        let Ok(file) = File::open(&path) else {
+            continue;
+        };
+        // EVIOCGBIT(EV_KEY)
+        if let Ok(bits) = ioctl_bits(&file, 0x20 + EV_KEY, KEY_MAX)
+            && (code as usize) <= KEY_MAX
+            && bit_set(&bits, code as usize)
+        {
+            files.push(file);
+        }
+    }
+    if files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no readable input device has the key with code {}", code),
+        ));
+    }
+
+    // A key that is down when its device is grabbed never has its release
+    // seen by anything else, so it sticks. Wait for everything to be let go,
+    // such as the Enter that started the script.
+    let deadline = Instant::now() + timeout;
+    while files.iter().any(keys_down) {
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut devices: Vec<Grabbed> = files.into_iter().map(Grabbed::new).collect();
+    let mut record = [0u8; mem::size_of::<libc::input_event>()];
+    loop {
+        if devices.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "every input device with the key went away",
+            ));
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(false);
+        }
+        let mut fds: Vec<libc::pollfd> = devices
+            .iter()
+            .map(|device| libc::pollfd {
+                fd: device.0.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let ret = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                left.as_millis().clamp(1, i32::MAX as u128) as libc::c_int,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        // Backwards, so that devices can be dropped along the way. One that
+        // was unplugged reports an error, or only hangs up, and is forgotten
+        // while the others are still waited on.
+        for (i, fd) in fds.iter().enumerate().rev() {
+            if fd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                devices.remove(i);
+                continue;
+            }
+            if fd.revents & libc::POLLIN == 0 {
+                continue;
+            }
+            // An event node only ever hands out whole events.
+            if devices[i].0.read_exact(&mut record).is_err() {
+                devices.remove(i);
+                continue;
+            }
+            let event: libc::input_event =
+                unsafe { ptr::read_unaligned(record.as_ptr() as *const libc::input_event) };
+            if event.type_ as u32 == EV_KEY && event.code == code && event.value == 1 {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// An existing event device that events are written to directly. The kernel
+/// drops events for keys the device did not declare, so those are known up
+/// front from its capabilities.
+pub struct EventWriter {
+    file: File,
+    keys: Vec<u8>,
+}
+
+impl EventWriter {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        // EVIOCGBIT(EV_KEY)
+        let keys = ioctl_bits(&file, 0x20 + EV_KEY, KEY_MAX)?;
+        Ok(EventWriter { file, keys })
+    }
+
+    /// Whether the device declared the key with this code.
+    pub fn has_key(&self, code: usize) -> bool {
+        code <= KEY_MAX && bit_set(&self.keys, code)
+    }
+
+    pub fn write(&mut self, kind: u16, code: u16, value: i32) -> io::Result<()> {
+        // The kernel timestamps injected events itself.
+        let event = libc::input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_: kind,
+            code,
+            value,
+        };
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                &event as *const libc::input_event as *const u8,
+                mem::size_of::<libc::input_event>(),
+            )
+        };
+        self.file.write_all(bytes)
+    }
+}
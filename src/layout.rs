@@ -0,0 +1,187 @@
+//! Character tables used by `type`, one per supported keyboard layout.
+//!
+//! Each table says which Linux key produces a character on that layout and
+//! which modifiers it needs. The key names are the ones from `--list-keys`.
+
+/// The key and modifiers that produce a character on a layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stroke {
+    pub key: String,
+    pub shift: bool,
+    pub altgr: bool,
+}
+
+impl Stroke {
+    fn plain(key: &str) -> Self {
+        Stroke {
+            key: key.to_string(),
+            shift: false,
+            altgr: false,
+        }
+    }
+
+    fn shifted(key: &str) -> Self {
+        Stroke {
+            shift: true,
+            ..Stroke::plain(key)
+        }
+    }
+
+    fn altgr(key: &str) -> Self {
+        Stroke {
+            altgr: true,
+            ..Stroke::plain(key)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// US QWERTY.
+    Us,
+    /// French AZERTY.
+    Fr,
+}
+
+impl Layout {
+    pub const ALL: &[Layout] = &[Layout::Us, Layout::Fr];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "us" => Ok(Layout::Us),
+            "fr" => Ok(Layout::Fr),
+            _ => Err(format!("unknown layout: {}", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Fr => "fr",
+        }
+    }
+
+    pub fn stroke(self, ch: char) -> Option<Stroke> {
+        if ch.is_ascii_uppercase() {
+            let base = self.stroke(ch.to_ascii_lowercase())?;
+            return Some(Stroke {
+                shift: true,
+                ..base
+            });
+        }
+
+        match ch {
+            ' ' => return Some(Stroke::plain("space")),
+            '\n' => return Some(Stroke::plain("enter")),
+            '\t' => return Some(Stroke::plain("tab")),
+            _ => {}
+        }
+
+        match self {
+            Layout::Us => us(ch),
+            Layout::Fr => fr(ch),
+        }
+    }
+}
+
+fn us(ch: char) -> Option<Stroke> {
+    let stroke = match ch {
+        'a'..='z' | '0'..='9' => Stroke::plain(&ch.to_string()),
+        '-' => Stroke::plain("minus"),
+        '=' => Stroke::plain("equal"),
+        '[' => Stroke::plain("leftbrace"),
+        ']' => Stroke::plain("rightbrace"),
+        ';' => Stroke::plain("semicolon"),
+        '\'' => Stroke::plain("apostrophe"),
+        '`' => Stroke::plain("grave"),
+        '\\' => Stroke::plain("backslash"),
+        ',' => Stroke::plain("comma"),
+        '.' => Stroke::plain("dot"),
+        '/' => Stroke::plain("slash"),
+        '!' => Stroke::shifted("1"),
+        '@' => Stroke::shifted("2"),
+        '#' => Stroke::shifted("3"),
+        '$' => Stroke::shifted("4"),
+        '%' => Stroke::shifted("5"),
+        '^' => Stroke::shifted("6"),
+        '&' => Stroke::shifted("7"),
+        '*' => Stroke::shifted("8"),
+        '(' => Stroke::shifted("9"),
+        ')' => Stroke::shifted("0"),
+        '_' => Stroke::shifted("minus"),
+        '+' => Stroke::shifted("equal"),
+        '{' => Stroke::shifted("leftbrace"),
+        '}' => Stroke::shifted("rightbrace"),
+        ':' => Stroke::shifted("semicolon"),
+        '"' => Stroke::shifted("apostrophe"),
+        '~' => Stroke::shifted("grave"),
+        '|' => Stroke::shifted("backslash"),
+        '<' => Stroke::shifted("comma"),
+        '>' => Stroke::shifted("dot"),
+        '?' => Stroke::shifted("slash"),
+        _ => return None,
+    };
+
+    Some(stroke)
+}
+
+fn fr(ch: char) -> Option<Stroke> {
+    let stroke = match ch {
+        'a' => Stroke::plain("q"),
+        'q' => Stroke::plain("a"),
+        'z' => Stroke::plain("w"),
+        'w' => Stroke::plain("z"),
+        'm' => Stroke::plain("semicolon"),
+        'b' | 'c' | 'd' | 'e' | 'f' | 'g' | 'h' | 'i' | 'j' | 'k' | 'l' | 'n' | 'o' | 'p' | 'r'
+        | 's' | 't' | 'u' | 'v' | 'x' | 'y' => Stroke::plain(&ch.to_string()),
+        // The digit row types symbols unless Shift is held.
+        '0'..='9' => Stroke::shifted(&ch.to_string()),
+        '&' => Stroke::plain("1"),
+        'é' => Stroke::plain("2"),
+        '"' => Stroke::plain("3"),
+        '\'' => Stroke::plain("4"),
+        '(' => Stroke::plain("5"),
+        '-' => Stroke::plain("6"),
+        'è' => Stroke::plain("7"),
+        '_' => Stroke::plain("8"),
+        'ç' => Stroke::plain("9"),
+        'à' => Stroke::plain("0"),
+        ')' => Stroke::plain("minus"),
+        '°' => Stroke::shifted("minus"),
+        '=' => Stroke::plain("equal"),
+        '+' => Stroke::shifted("equal"),
+        '$' => Stroke::plain("rightbrace"),
+        '£' => Stroke::shifted("rightbrace"),
+        'ù' => Stroke::plain("apostrophe"),
+        '%' => Stroke::shifted("apostrophe"),
+        '*' => Stroke::plain("backslash"),
+        'µ' => Stroke::shifted("backslash"),
+        '²' => Stroke::plain("grave"),
+        '<' => Stroke::plain("102nd"),
+        '>' => Stroke::shifted("102nd"),
+        ',' => Stroke::plain("m"),
+        '?' => Stroke::shifted("m"),
+        ';' => Stroke::plain("comma"),
+        '.' => Stroke::shifted("comma"),
+        ':' => Stroke::plain("dot"),
+        '/' => Stroke::shifted("dot"),
+        '!' => Stroke::plain("slash"),
+        '§' => Stroke::shifted("slash"),
+        '~' => Stroke::altgr("2"),
+        '#' => Stroke::altgr("3"),
+        '{' => Stroke::altgr("4"),
+        '[' => Stroke::altgr("5"),
+        '|' => Stroke::altgr("6"),
+        '`' => Stroke::altgr("7"),
+        '\\' => Stroke::altgr("8"),
+        '^' => Stroke::altgr("9"),
+        '@' => Stroke::altgr("0"),
+        ']' => Stroke::altgr("minus"),
+        '}' => Stroke::altgr("equal"),
+        '€' => Stroke::altgr("e"),
+        '¤' => Stroke::altgr("rightbrace"),
+        _ => return None,
+    };
+
+    Some(stroke)
+}
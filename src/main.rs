@@ -1,13 +1,437 @@
 #![feature(str_split_whitespace_remainder)]
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::io::{self, BufRead};
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::ptr;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, mpsc};
 use std::thread;
-use std::time::Duration;
-use uinput::event::keyboard::{Key, Keyboard, Misc};
+use std::time::{Duration, Instant};
+use uinput::event::keyboard::{Key, KeyPad, Keyboard, Misc};
 use uinput::Device;
+use uinput::event::{Code, Kind};
+
+mod delay;
+mod diff;
+mod evdev;
+mod layout;
+mod normalize;
+mod profile;
+use delay::{Delay, Rng};
+use diff::Edit;
+use evdev::{EventWriter, Led};
+use layout::Layout;
+use normalize::Normalization;
+use profile::{quote, quote_key};
+
+/// Destination for key events. The interpreter only talks to this trait so it
+/// can run against a real uinput device or a recorder in tests.
+trait KeySink {
+    fn press(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>>;
+    fn release(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>>;
+    /// An autorepeat event, as the kernel sends for a key that is held.
+    fn repeat(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>>;
+    fn synchronize(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Any event at all, for --replay-raw.
+    fn raw(
+        &mut self,
+        _kind: u16,
+        _code: u16,
+        _value: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("this device cannot send raw events".into())
+    }
+
+    /// Whether the sink was set up to emit `key`. Chords are checked against
+    /// this before any of their events are sent.
+    fn supports(&self, _key: &Keyboard) -> bool {
+        true
+    }
+}
+
+impl KeySink for Device {
+    fn press(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(Device::press(self, key)?)
+    }
+
+    fn release(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(Device::release(self, key)?)
+    }
+
+    fn repeat(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(Device::send(self, *key, 2)?)
+    }
+
+    fn synchronize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(Device::synchronize(self)?)
+    }
+
+    fn raw(&mut self, kind: u16, code: u16, value: i32) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(Device::write(self, kind.into(), code.into(), value)?)
+    }
+}
+
+impl KeySink for EventWriter {
+    fn press(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.write(key.kind() as u16, key.code() as u16, 1)?)
+    }
+
+    fn release(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.write(key.kind() as u16, key.code() as u16, 0)?)
+    }
+
+    fn repeat(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.write(key.kind() as u16, key.code() as u16, 2)?)
+    }
+
+    fn synchronize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // EV_SYN, SYN_REPORT
+        Ok(self.write(0, 0, 0)?)
+    }
+
+    fn raw(&mut self, kind: u16, code: u16, value: i32) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.write(kind, code, value)?)
+    }
+
+    fn supports(&self, key: &Keyboard) -> bool {
+        self.has_key(key.code() as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Emission {
+    Press(Keyboard),
+    Release(Keyboard),
+    Repeat(Keyboard),
+    Sync,
+    Raw(u16, u16, i32),
+}
+
+type Request = (Emission, mpsc::Sender<Result<(), String>>);
+
+/// How long finishing waits for the worker to send one more queued event
+/// before giving up, when that is longer than the per-action timeout.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A sink driven from a worker thread, so that a write which blocks (for
+/// example behind a stuck compositor) costs one action instead of hanging
+/// dotool. Emissions that take longer than the timeout are reported as
+/// errors; the worker carries on with the queue once the write returns.
+///
+/// Only the created uinput keyboard is wrapped, which supports every key, so
+/// `supports` is not passed through to the worker.
+struct TimeoutDevice<S> {
+    requests: Option<mpsc::Sender<Request>>,
+    worker: Option<thread::JoinHandle<S>>,
+    /// How many emissions the worker has finished, to tell a slow device
+    /// draining its queue from a stuck one.
+    done: Arc<AtomicUsize>,
+    timeout: Duration,
+}
+
+impl<S: KeySink + Send + 'static> TimeoutDevice<S> {
+    fn new(mut device: S, timeout: Duration) -> Self {
+        let (requests, queue) = mpsc::channel::<Request>();
+        let done = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::clone(&done);
+        let worker = thread::spawn(move || {
+            for (emission, reply) in queue {
+                let result = match emission {
+                    Emission::Press(key) => device.press(&key),
+                    Emission::Release(key) => device.release(&key),
+                    Emission::Repeat(key) => device.repeat(&key),
+                    Emission::Sync => device.synchronize(),
+                    Emission::Raw(kind, code, value) => device.raw(kind, code, value),
+                };
+                finished.fetch_add(1, Ordering::Relaxed);
+                // Nobody is listening if the caller already gave up.
+                let _ = reply.send(result.map_err(|e| e.to_string()));
+            }
+            device
+        });
+        TimeoutDevice {
+            requests: Some(requests),
+            worker: Some(worker),
+            done,
+            timeout,
+        }
+    }
+}
+
+impl<S> TimeoutDevice<S> {
+    fn emit(&self, emission: Emission) -> Result<(), Box<dyn std::error::Error>> {
+        let (reply, result) = mpsc::channel();
+        self.requests
+            .as_ref()
+            .and_then(|requests| requests.send((emission, reply)).ok())
+            .ok_or("device worker has stopped")?;
+        match result.recv_timeout(self.timeout) {
+            Ok(result) => Ok(result?),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(format!(
+                "device did not accept the event within {}ms",
+                self.timeout.as_millis()
+            )
+            .into()),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err("device worker has stopped".into()),
+        }
+    }
+
+    /// Lets the worker send what is still queued from emissions that timed
+    /// out, and hands back the sink. Gives up, leaving those events unsent,
+    /// if the device stops accepting them altogether.
+    fn finish(&mut self) -> Option<S> {
+        self.requests.take();
+        let worker = self.worker.take()?;
+        let (mut seen, mut since) = (self.done.load(Ordering::Relaxed), Instant::now());
+        while !worker.is_finished() {
+            let done = self.done.load(Ordering::Relaxed);
+            if done != seen {
+                (seen, since) = (done, Instant::now());
+            } else if since.elapsed() >= self.timeout.max(DRAIN_TIMEOUT) {
+                warn("the device stopped accepting events; exiting with some unsent");
+                return None;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        worker.join().ok()
+    }
+}
+
+impl<S> Drop for TimeoutDevice<S> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl<S> KeySink for TimeoutDevice<S> {
+    fn press(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        self.emit(Emission::Press(*key))
+    }
+
+    fn release(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        self.emit(Emission::Release(*key))
+    }
+
+    fn repeat(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        self.emit(Emission::Repeat(*key))
+    }
+
+    fn synchronize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.emit(Emission::Sync)
+    }
+
+    fn raw(&mut self, kind: u16, code: u16, value: i32) -> Result<(), Box<dyn std::error::Error>> {
+        self.emit(Emission::Raw(kind, code, value))
+    }
+}
+
+/// A key combination --safe-mode refuses: the key, pressed while at least one
+/// key of every modifier group is down.
+#[derive(Debug, Clone, PartialEq)]
+struct Blocked {
+    name: String,
+    key: u16,
+    modifiers: Vec<Vec<u16>>,
+}
+
+impl Blocked {
+    fn new(name: &str, chord: &Chord) -> Self {
+        let codes = |keys: &[Key]| keys.iter().map(|key| key.code() as u16).collect();
+        let mut modifiers = Vec::new();
+        if chord.super_key {
+            modifiers.push(codes(&[Key::LeftMeta, Key::RightMeta]));
+        }
+        if chord.ctrl {
+            modifiers.push(codes(&[Key::LeftControl, Key::RightControl]));
+        }
+        // Right Alt is Alt on layouts without AltGr, so it counts for both.
+        if chord.alt {
+            modifiers.push(codes(&[Key::LeftAlt, Key::RightAlt]));
+        }
+        if chord.altgr {
+            modifiers.push(codes(&[Key::RightAlt]));
+        }
+        if chord.shift {
+            modifiers.push(codes(&[Key::LeftShift, Key::RightShift]));
+        }
+        Blocked {
+            name: name.to_string(),
+            key: chord.key.code() as u16,
+            modifiers,
+        }
+    }
+
+    fn matches(&self, key: u16, held: &[u16]) -> bool {
+        key == self.key
+            && self
+                .modifiers
+                .iter()
+                .all(|group| group.iter().any(|code| held.contains(code)))
+    }
+}
+
+/// What `default` stands for in --safe-block: switching VTs, the
+/// Ctrl+Alt+Delete and X server kill shortcuts, and SysRq.
+const SAFE_BLOCK_DEFAULT: &[&str] = &[
+    "ctrl+alt+f1",
+    "ctrl+alt+f2",
+    "ctrl+alt+f3",
+    "ctrl+alt+f4",
+    "ctrl+alt+f5",
+    "ctrl+alt+f6",
+    "ctrl+alt+f7",
+    "ctrl+alt+f8",
+    "ctrl+alt+f9",
+    "ctrl+alt+f10",
+    "ctrl+alt+f11",
+    "ctrl+alt+f12",
+    "ctrl+alt+delete",
+    "ctrl+alt+backspace",
+    "sysrq",
+];
+
+/// The combinations --safe-mode refuses, from the --safe-block entries.
+fn safe_blocklist(
+    options: &Options,
+    linux_keys: &HashMap<String, Keyboard>,
+) -> Result<Vec<Blocked>, String> {
+    let mut blocked = Vec::new();
+    for entry in &options.safe_block {
+        let names = match entry.as_str() {
+            "default" => SAFE_BLOCK_DEFAULT.to_vec(),
+            entry => vec![entry],
+        };
+        for name in names {
+            let chord = parse_chord(name, linux_keys, options)
+                .map_err(|e| format!("invalid safe-block entry: {}", e))?;
+            blocked.push(Blocked::new(name, &chord));
+        }
+    }
+    Ok(blocked)
+}
+
+/// Counts the events passed on to the sink it wraps, for --stats, and retries
+/// those that fail under --key-retries. Under --safe-mode it also drops the
+/// presses of blocked combinations, which leaves their releases harmless.
+struct Counting<S> {
+    sink: S,
+    pressed: usize,
+    emitted: usize,
+    /// How many times a failed event is tried again.
+    retries: u32,
+    retried: usize,
+    /// Combinations refused under --safe-mode, and the codes of the keys
+    /// currently down, which they are checked against.
+    blocked: Vec<Blocked>,
+    held: Vec<u16>,
+}
+
+impl<S> Counting<S> {
+    fn new(sink: S, retries: u32) -> Self {
+        Counting {
+            sink,
+            pressed: 0,
+            emitted: 0,
+            retries,
+            retried: 0,
+            blocked: Vec::new(),
+            held: Vec::new(),
+        }
+    }
+
+    /// Whether a press of `code` may go out, warning if not. Keeps track of
+    /// the keys that are down.
+    fn allow(&mut self, code: u16, value: i32) -> bool {
+        if self.blocked.is_empty() {
+            return true;
+        }
+        if value == 0 {
+            self.held.retain(|held| *held != code);
+            return true;
+        }
+        if let Some(blocked) = self.blocked.iter().find(|b| b.matches(code, &self.held)) {
+            warn(&format!("--safe-mode blocked {}", blocked.name));
+            return false;
+        }
+        if !self.held.contains(&code) {
+            self.held.push(code);
+        }
+        true
+    }
+
+    /// Sends one event, backing off 1ms more before each retry.
+    fn send(
+        &mut self,
+        mut event: impl FnMut(&mut S) -> Result<(), Box<dyn std::error::Error>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tries = 0;
+        loop {
+            match event(&mut self.sink) {
+                Ok(()) => {
+                    self.emitted += 1;
+                    return Ok(());
+                }
+                Err(_) if tries < self.retries => {
+                    tries += 1;
+                    self.retried += 1;
+                    thread::sleep(Duration::from_millis(tries.into()));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<S: KeySink> KeySink for Counting<S> {
+    fn press(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.allow(key.code() as u16, 1) {
+            return Ok(());
+        }
+        self.send(|sink| sink.press(key))?;
+        self.pressed += 1;
+        Ok(())
+    }
+
+    fn release(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        self.allow(key.code() as u16, 0);
+        self.send(|sink| sink.release(key))
+    }
+
+    fn repeat(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.allow(key.code() as u16, 2) {
+            return Ok(());
+        }
+        self.send(|sink| sink.repeat(key))
+    }
+
+    fn synchronize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.send(|sink| sink.synchronize())
+    }
+
+    fn raw(&mut self, kind: u16, code: u16, value: i32) -> Result<(), Box<dyn std::error::Error>> {
+        if kind == EV_KEY && !self.allow(code, value) {
+            return Ok(());
+        }
+        self.send(|sink| sink.raw(kind, code, value))?;
+        if kind == EV_KEY && value == 1 {
+            self.pressed += 1;
+        }
+        Ok(())
+    }
+
+    fn supports(&self, key: &Keyboard) -> bool {
+        self.sink.supports(key)
+    }
+}
 
 #[derive(Debug, Clone)]
 struct Chord {
@@ -31,122 +455,611 @@ impl Chord {
         }
     }
 
-    fn key_down(&self, device: &mut Device) -> Result<(), Box<dyn std::error::Error>> {
-        if self.super_key {
-            device.press(&Key::LeftMeta)?;
-        }
-        if self.altgr {
-            device.press(&Key::RightAlt)?;
+    /// Every key the chord presses, modifiers first.
+    fn keys(&self, modifiers: &ModifierKeys) -> Vec<Keyboard> {
+        let mut keys = self.modifier_keys(modifiers);
+        keys.push(self.key);
+        keys
+    }
+
+    /// The modifier flags, in the order of `ModifierKeys::keys`.
+    fn flags(&self) -> [bool; 5] {
+        [self.super_key, self.altgr, self.ctrl, self.alt, self.shift]
+    }
+
+    /// The keys for the chord's modifiers, in the order they are pressed.
+    fn modifier_keys(&self, modifiers: &ModifierKeys) -> Vec<Keyboard> {
+        self.flags()
+            .into_iter()
+            .zip(modifiers.keys())
+            .filter(|(flag, _)| *flag)
+            .map(|(_, key)| Keyboard::Key(key))
+            .collect()
+    }
+
+    /// The chord less the modifiers in `down`, so that a chord pressed while
+    /// `keydown` holds some of its modifiers neither presses them again nor
+    /// lets them go.
+    fn without(&self, down: &[Keyboard], modifiers: &ModifierKeys) -> Chord {
+        let mut flags = self.flags();
+        for (flag, key) in flags.iter_mut().zip(modifiers.keys()) {
+            *flag &= !down.contains(&Keyboard::Key(key));
         }
-        if self.ctrl {
-            device.press(&Key::LeftControl)?;
+        let [super_key, altgr, ctrl, alt, shift] = flags;
+        Chord {
+            super_key,
+            altgr,
+            ctrl,
+            alt,
+            shift,
+            key: self.key,
         }
-        if self.alt {
-            device.press(&Key::LeftAlt)?;
+    }
+
+    fn press_modifiers(
+        &self,
+        device: &mut impl KeySink,
+        modifiers: &ModifierKeys,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for key in self.modifier_keys(modifiers) {
+            device.press(&key)?;
         }
-        if self.shift {
-            device.press(&Key::LeftShift)?;
+        Ok(())
+    }
+
+    fn release_modifiers(
+        &self,
+        device: &mut impl KeySink,
+        modifiers: &ModifierKeys,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for key in self.modifier_keys(modifiers).iter().rev() {
+            device.release(key)?;
         }
+        Ok(())
+    }
+
+    fn key_down(
+        &self,
+        device: &mut impl KeySink,
+        modifiers: &ModifierKeys,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.press_modifiers(device, modifiers)?;
         device.press(&self.key)?;
         device.synchronize()?;
         Ok(())
     }
 
-    fn key_up(&self, device: &mut Device) -> Result<(), Box<dyn std::error::Error>> {
+    fn key_up(
+        &self,
+        device: &mut impl KeySink,
+        modifiers: &ModifierKeys,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         device.release(&self.key)?;
-        if self.shift {
-            device.release(&Key::LeftShift)?;
-        }
-        if self.alt {
-            device.release(&Key::LeftAlt)?;
-        }
-        if self.ctrl {
-            device.release(&Key::LeftControl)?;
-        }
-        if self.altgr {
-            device.release(&Key::RightAlt)?;
-        }
-        if self.super_key {
-            device.release(&Key::LeftMeta)?;
-        }
+        self.release_modifiers(device, modifiers)?;
         device.synchronize()?;
         Ok(())
     }
 }
 
-fn usage() {
-    println!(
+fn usage(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(
+        out,
         "dotool reads actions from stdin and simulates input using uinput.
 
 The supported actions are:
     key CHORD...
+    seq NAME...
+    sequence CHORD:MILLISECONDS...
+    at MILLISECONDS ACTION
+    ifenv VAR VALUE ACTION
+    ifset VAR ACTION
     keydown CHORD...
     keyup CHORD...
+    press KEY...
+    release KEY...
+    sync
+    raw KEY {{0,1,2}}
+    roll [-nest] KEY...
+    burst KEY COUNT MILLISECONDS
+    with CHORD...
+    end
+    select [char,word,line] {{left,right,up,down}} [COUNT]
     type TEXT
-    keydelay MILLISECONDS
+    typefile PATH
+    keydelay MILLISECONDS[-MILLISECONDS]
     keyhold MILLISECONDS
-    typedelay MILLISECONDS
+    typedelay MILLISECONDS[-MILLISECONDS]
     typehold MILLISECONDS
+    worddelay MILLISECONDS[-MILLISECONDS]
+    defaults
+    layout NAME
+    echo MESSAGE
+    deadline MILLISECONDS
+    checkdeadline
+    requirekeymap HASH
+    waitled {{caps,num,scroll}} {{on,off}}
+    waitkey KEY
+    repeat-until CONDITION ACTION
+    caps {{on,off}}
+    num {{on,off}}
+    scroll {{on,off}}
+
+seq presses the chords of a named sequence: save, selectall, copy, cut, paste,
+undo and redo are built in, and a [seq] table in the profile adds more, such
+as saveas = \"ctrl+shift+s\". Separate several chords with spaces.
+
+sequence presses each chord in turn like key, but holds it for the time given
+after it instead of keyhold, as in sequence a:50 b:30 ctrl+c:100.
+
+at runs its action that long from now without waiting for it: the lines after
+it go on as usual and the action runs between them when its time comes, as in
+keydown shift then at 300 keyup shift. Actions still waiting at the end of
+input run at their time before dotool exits.
+
+ifenv runs its action only if the environment variable VAR is VALUE, and
+ifset only if VAR is set at all, even to nothing, so one script can press
+different shortcuts on different desktops, as in ifenv XDG_CURRENT_DESKTOP
+KDE key ctrl+alt+t. A VALUE with spaces goes in double quotes.
+
+type types the rest of the line after the single space that follows it,
+leading and trailing spaces included, so a line of only spaces types them.
+Quotes in it are typed like any other character.
+Actions can be indented; only the whitespace before the keyword is ignored.
+
+typefile takes the rest of the line as its path. A path in double quotes can
+start or end with spaces, and \\\" and \\\\ in it stand for a quote and a
+backslash.
+
+layout switches the layout type assumes for the rest of the script, as
+--layout does for the whole of it.
+
+echo prints the rest of the line to stderr after dotool:, or with --echo as it
+is to stdout, to show how far a script has got. It sends nothing.
+
+deadline sets a deadline that long from now, and checkdeadline warns if it has
+passed, or under --strict stops dotool with an error. A script can check the
+same deadline more than once; a later deadline replaces it.
+
+requirekeymap warns if HASH is not what --keymap-hash prints, meaning this
+dotool names its keys differently from the one the script was written for, or
+under --strict stops dotool with an error.
+
+worddelay is the typedelay of spaces, tabs and newlines that type and typefile
+type, so that words can be further apart than their letters. Until it is set
+it is the same as typedelay.
+
+defaults puts keydelay, keyhold, typedelay, typehold and worddelay back to
+their built-in values, whatever the flags and profile set them to, and empties
+the hold-map, so a section of a script can start from known timing.
+
+keydelay, typedelay and worddelay take a range such as 2-10 to wait a random
+time within it after every event. A new keydelay applies from the next chord on,
+so every chord on one key line waits the same; for different waits, change
+keydelay between key lines or use sequence. Any of the delays and holds can have
+a fraction, as in 0.5, or be 0: a press and its release still go out with a sync
+after each, so they reach applications as two separate events, only with nothing
+between them.
+
+The key of a chord can also be a character, or the X11 name of a symbol such as
+colon, at or question, and the chord then adds the Shift or AltGr that the
+layout types it with: ctrl+colon and ctrl+: are both Ctrl+Shift+Semicolon on us
+and Ctrl+Dot on fr, and ctrl+at is Ctrl+AltGr+0 on fr.
+
+Any key can also be given by its Linux code, in decimal or hex, as evtest
+shows it: code:30 and code:0x1e are both a. A code with no key warns with the
+nearest code that has one.
+
+Modifiers held with keydown, such as keydown ctrl then keydown shift, apply to
+the key and type actions that follow and stay held through them until keyup.
+
+with holds its chords like keydown until the matching end, which releases
+them. Blocks nest, and an inner end leaves the keys of outer blocks held.
+
+select holds Shift and presses an arrow COUNT times (default 1), as a shorter
+with shift, key right right right, end. word adds Ctrl, to select by words in
+most applications, and line selects to the start or end of the line with Home
+or End for left and right, or a line at a time for up and down.
+
+press and release emit a single key event each, with no modifiers, delays or
+synchronization; nothing reaches applications until a sync action. raw sends
+one event with the given value, 0 for release, 1 for press or 2 for
+autorepeat, followed by a sync.
 
---list-keys    Print the possible Linux keys and exit.
---version      Print the version and exit.
+roll presses its keys one after another and only then releases them, in the
+same order or, with -nest, the reverse one, keyhold apart, for testing how
+applications handle rollover.
+
+burst taps KEY COUNT times, MILLISECONDS apart from one press to the next, with
+each release straight after its press, the way a bouncing switch or a rapid
+tapper would, for testing debouncing. With --verbose it reports the rate it
+reached.
+
+waitled reads the lock LEDs from a real keyboard, which needs read access to
+it (usually membership of the input group). caps, num and scroll use the
+same LEDs to press the lock key only when it is not already in that state.
+
+repeat-until runs its action again and again until the condition holds,
+checking it before each run, as in repeat-until nofile /tmp/dialog key enter.
+The conditions are file PATH and nofile PATH, for whether PATH exists, and led
+{{caps,num,scroll}} {{on,off}}, read as waitled reads it; a PATH with spaces
+goes in double quotes. It warns and goes on if --wait-timeout passes first.
+There is no window condition: Wayland gives other programs no way to see
+which windows are open.
+
+waitkey waits for KEY to be pressed on a real keyboard, as a checkpoint for
+someone watching the script, and warns if --wait-timeout passes first. It
+needs the same read access as waitled. While it waits it takes the keyboards
+for itself, so the key, and anything else typed before it, reaches no
+application. It first waits for any keys held down to be let go, so that
+their release is not lost.
+
+--list-keys [PATTERN]
+               Print the possible Linux keys, or those whose name contains
+               PATTERN, and exit.
+--keymap-hash  Print a hash of the key names and codes, for requirekeymap, and
+               exit.
+--version [--verbose]
+               Print the version and exit. With --verbose, also print the
+               backends, the kernel release, whether /dev/uinput is writable
+               and its protocol version, and the layouts.
+--show-config  Print the settings in effect, after the profile and flags
+               are applied, in profile syntax and exit.
+--verify-balance
+               Read a script from stdin without running it, report keys that
+               keydown or press leave held and keys released that were never
+               pressed, and exit. Actions after at, ifset, ifenv and
+               repeat-until count as if they run.
+--compare A B  Run the scripts A and B without sending anything or waiting,
+               print the events one sends and the other does not with the
+               line that sends them, and exit, with an error if there are
+               any. LEDs are not waited for, and at actions run in turn.
+--calibrate    After 3s to focus an empty text field, type a test line at each
+               of a falling series of delays, labelled with the delay, and
+               exit. The fastest line that arrives intact is a good value
+               for --typedelay and --typehold.
+--profile PATH Read settings from a TOML file. Its keys are the names of the
+               flags below without the dashes. Given more than once, the
+               files are read in order. Flags on the command line take
+               precedence.
+--keydelay MS, --keyhold MS, --typedelay MS, --typehold MS
+               Initial values for the matching actions.
+--worddelay MS Initial value for worddelay, which is the same as typedelay
+               unless set.
+--seed N       Seed for the random delays of keydelay and typedelay ranges,
+               to repeat a run exactly. Without it the seed comes from the
+               clock, and --verbose prints the one chosen, as does
+               --show-config in a comment.
+--layout NAME  Keyboard layout type assumes: us (the default) or fr.
+--cpm N        Set typedelay so type averages N characters per minute.
+--wpm N        Same as --cpm with five characters per word.
+--hold-map KEY=MS,...
+               Hold these keys for MS instead of keyhold in the key action.
+               KEY is any name a chord takes for its key, and an entry
+               applies whichever name the script uses.
+--use-device PATH
+               Write events to an existing /dev/input/eventN device instead
+               of creating a keyboard. This needs write access to it, and
+               only the keys the device declares can be sent; chords with
+               other keys are rejected.
+--led-device PATH
+               The /dev/input/eventN device to read LEDs from. By default
+               the first readable device with the LED is used.
+--initial-locks {{num,caps,scroll}},...
+               Turn these lock keys on before the first action, whatever
+               state the session left them in.
+--restore-locks
+               At the end of input, put the locks --initial-locks changed
+               back the way they were.
+--wait-timeout MS
+               How long waiting actions wait before giving up (default 5000).
+--action-timeout MS
+               Give up on a key event the device has not accepted after MS,
+               warn and go on with the next action.
+--max-hold MS
+               Release a chord pressed with keydown, with a warning, when its
+               keyup has not come after MS.
+--focus-settle MS
+               Wait MS more after releasing a chord with Super or Alt in it,
+               such as alt+tab, so the window it brings up has focus before
+               the next action (default 0).
+--warn-slow MS Warn about every action that takes MS or longer, with its
+               line number.
+--verbose      Report more about what actions did, such as the rate burst
+               reached.
+--stats        At the end of input, print the keys pressed, characters typed,
+               warnings, retries under --key-retries, slow actions under
+               --warn-slow, time taken and events per second to stderr.
+--type-stdin   Type everything read from stdin, as typefile would, instead of
+               reading actions from it. Typing starts with the first read,
+               however large the input.
+--replay-raw   Replay stdin as the binary input_event records a real device
+               gives when its /dev/input/eventN node is read, as in
+               `cat /dev/input/event3 > capture`, keeping their timing. The
+               records are in this machine's native layout and byte order,
+               so a capture replays on the kind of machine it was made on.
+               Events of types the keyboard does not have, such as the
+               EV_MSC scancodes real keyboards send, are dropped by the
+               kernel. Keys still down at the end are released. The
+               timestamps themselves are not sent on: the kernel stamps
+               every event a uinput or event device is given with the time
+               it arrives, and ignores the time written with it, so no
+               dotool event can carry a fixed or replayed timestamp.
+--type-escapes In type, take a backslash to mean the next character is typed
+               as it is, so \\\\ types a backslash and \\; a semicolon. A
+               backslash at the end of the line is typed as it is. typefile
+               is not affected.
+--type-markup  In type, press the chords between braces as key does instead
+               of typing them, as in type hello{{enter}}world{{ctrl+s}}. {{{{
+               types a brace.
+--echo         Print the messages of echo to stdout as they are, instead of to
+               stderr after dotool:. Warnings stay on stderr.
+--progress     Show on stderr how far through its text each type and typefile
+               is, as a percentage updated in place.
+--coalesce-repeats
+               In type, keep the modifiers of a repeated character held for
+               the whole run instead of toggling them for every character.
+--create-retries N
+               Retry creating the virtual keyboard N times, backing off
+               from 100ms up to 5s, before giving up.
+--bus {{usb,bluetooth,virtual}}
+               The bus type the virtual keyboard reports. By default it is
+               left at 0, which matches none of these.
+--minimal-caps Give the virtual keyboard only the keys of --list-keys, instead
+               of every key Linux defines, such as the Braille, camera and
+               touchpad buttons no keyboard has. Software that checks what a
+               device can send then sees something closer to a real
+               keyboard. --replay-raw events for other keys are dropped.
+--queue-depth N
+               Read at most N lines of actions ahead of the one running
+               (default 1024). A producer that writes faster than dotool types
+               is then held back by the pipe instead of growing dotool's
+               memory, and a deeper queue only helps if it writes in bursts
+               that dotool can catch up on between them. 0 reads each line
+               only when the previous one is done.
+--key-retries N
+               Try a key event the device rejects up to N more times, 1ms
+               longer apart each time, before warning. Not used with
+               --action-timeout, since an event that timed out is still sent.
+--type-newline-as {{enter,shift-enter,none}}
+               What type and typefile press for a newline. shift-enter suits
+               chat apps that send on Enter; none leaves newlines out.
+--no-startup-delay
+               Start reading actions as soon as the keyboard is created,
+               instead of waiting up to 1s for its /dev/input node. This also
+               skips checking which keys the kernel gave the keyboard.
+--wake-key     Tap Left Ctrl before the first action. Some compositors drop
+               the first event a new device sends, which would otherwise be
+               the first key of the script. On its own the tap does nothing
+               in most applications.
+--normalize {{nfc,nfd,none}}
+               How type and typefile normalize accented letters. nfc (the
+               default) joins a letter and a combining accent into the
+               single character the layout knows.
+--type-transform {{none,upper,lower,reverse}}
+               Change the text of type and typefile before typing it:
+               upper or lower case it, or reverse it.
+--unicode-method {{none,alt-numpad}}
+               How type enters characters the layout has no key for. none
+               (the default) skips them with a warning. alt-numpad holds Alt
+               and types the decimal code point on the keypad, which only
+               some Windows compatibility layers understand, usually only
+               below U+10000 and with Num Lock on.
+--char-hook CMD
+               For a character the layout has no key for, run CMD with sh,
+               the code point (such as U+20AC) as $1, and type what it prints
+               less one trailing newline. This comes before --unicode-method.
+               CMD runs as the dotool user for any such character in the
+               input, so whoever writes the input can make it run: use only
+               a command that is safe with any argument.
+--super-key SIDE, --ctrl-key SIDE, --alt-key SIDE, --shift-key SIDE
+               Whether the modifier of that name in chords presses the left
+               (the default) or right key.
+--strict       Warn when key, keydown or with would hold both keys of a
+               modifier pair, such as both Shift keys or Alt and AltGr,
+               counting those keydown already holds. Also makes a passed
+               checkdeadline or requirekeymap, or keys of --list-keys the
+               kernel left off the keyboard, stop dotool instead of warning.
+--safe-mode    Refuse to press the key combinations in --safe-block, warning
+               instead, however the script tries to send them: with key,
+               keydown, press, raw, type or --replay-raw, and with the
+               modifiers held by any of them.
+--safe-block CHORD,...
+               The combinations --safe-mode refuses. A combination also
+               matches with more modifiers held, and with either side of
+               each. default (the default) stands for Ctrl+Alt+F1 to F12,
+               Ctrl+Alt+Delete, Ctrl+Alt+Backspace and SysRq, so
+               default,ctrl+alt+kpdot adds to them and ctrl+alt+delete
+               refuses only that.
+--no-auto-shift
+               Do not add Shift to chords whose key is an uppercase letter.
+--type-controls {{map,drop,warn}}
+               How type handles control characters. map (the default) types
+               CR as Enter, BS, ESC and DEL as their keys and drops the rest.
 
 See 'man dotool' for the documentation."
-    );
+    )
 }
 
 fn inform(msg: &str) {
     eprintln!("dotool: {}", msg);
 }
 
+/// How many warnings have been printed, for --stats.
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
 fn warn(msg: &str) {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
     eprintln!("dotool: WARNING: {}", msg);
 }
 
-fn parse_chord(chord_str: &str, linux_keys: &HashMap<String, Keyboard>) -> Result<Chord, String> {
-    let parts: Vec<&str> = chord_str.split('+').collect();
+/// Finds a key by its name in the table or its X11 keysym name, ignoring case,
+/// or by its Linux code as in `code:30`.
+fn lookup_key(name: &str, linux_keys: &HashMap<String, Keyboard>) -> Option<Keyboard> {
+    let name = name.to_lowercase();
+    if let Some(code) = name.strip_prefix("code:") {
+        return parse_key_code(code).and_then(|code| key_codes().get(&code).map(|(_, key)| *key));
+    }
+    linux_keys
+        .get(&name)
+        .or_else(|| x11_keysym_alias(&name).and_then(|alias| linux_keys.get(alias)))
+        .copied()
+}
 
-    if parts.is_empty() {
-        return Err("empty chord".to_string());
+/// A key code in decimal, or in hex after 0x.
+fn parse_key_code(code: &str) -> Option<u16> {
+    match code.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => code.parse().ok(),
     }
+}
 
-    let key_part = parts[parts.len() - 1];
+/// Every key dotool can press by code, with the name `--list-keys` gives it.
+fn key_codes() -> &'static BTreeMap<u16, (String, Keyboard)> {
+    static KEY_CODES: OnceLock<BTreeMap<u16, (String, Keyboard)>> = OnceLock::new();
+    KEY_CODES.get_or_init(|| {
+        let mut codes = BTreeMap::new();
+        for (name, key) in init_linux_keys() {
+            let entry = codes
+                .entry(key.code() as u16)
+                .or_insert_with(|| (name.clone(), key));
+            // Of the names for one key, the same one key_name picks.
+            if name < entry.0 {
+                entry.0 = name;
+            }
+        }
+        codes
+    })
+}
 
-    let key = linux_keys
-        .get(&key_part.to_lowercase())
-        .ok_or_else(|| format!("impossible key for layout: {}", key_part))?
-        .clone();
+/// Why `name` is not a key. For a `code:N` with no key, that names the key
+/// with the nearest code, to go on from.
+fn unknown_key(name: &str) -> String {
+    let Some(code) = name
+        .to_lowercase()
+        .strip_prefix("code:")
+        .map(str::to_string)
+    else {
+        return format!("impossible key for layout: {}", name);
+    };
+    let Some(code) = parse_key_code(&code) else {
+        return format!("invalid key code: {}", name);
+    };
+    let codes = key_codes();
+    let below = codes.range(..code).next_back();
+    let above = codes.range(code..).next();
+    let nearest = match (below, above) {
+        (Some(below), Some(above)) if code - below.0 <= above.0 - code => below,
+        (_, Some(above)) => above,
+        (Some(below), None) => below,
+        (None, None) => return format!("no key has code {}", code),
+    };
+    format!(
+        "no key has code {}; the nearest is code:{} ({})",
+        code, nearest.0, nearest.1.0
+    )
+}
 
-    let mut chord = Chord::new(key);
+fn parse_chord(
+    chord_str: &str,
+    linux_keys: &HashMap<String, Keyboard>,
+    options: &Options,
+) -> Result<Chord, String> {
+    // A trailing "++" means the key itself is '+'.
+    let (modifiers, key_part) = if chord_str == "+" {
+        ("", "+")
+    } else if let Some(modifiers) = chord_str.strip_suffix("++") {
+        (modifiers, "+")
+    } else {
+        chord_str.rsplit_once('+').unwrap_or(("", chord_str))
+    };
 
-    // Check if uppercase letter
-    if key_part.len() == 1 && key_part.chars().next().unwrap().is_uppercase() {
-        chord.shift = true;
+    if key_part.is_empty() {
+        return Err(format!("missing key in chord: {}", chord_str));
     }
 
-    for i in 0..parts.len() - 1 {
-        match parts[i].to_lowercase().as_str() {
+    let key = lookup_key(key_part, linux_keys).or_else(|| {
+        // A modifier on its own, as in `keydown ctrl`.
+        options.modifiers.by_name(key_part).map(Keyboard::Key)
+    });
+    let mut chord = match key {
+        Some(key) => {
+            let mut chord = Chord::new(key);
+
+            // Check if uppercase letter
+            if options.auto_shift
+                && key_part.len() == 1
+                && key_part.chars().next().unwrap().is_uppercase()
+            {
+                chord.shift = true;
+            }
+            chord
+        }
+        // Any other single character or symbol name is pressed the way type
+        // would produce it, so its own Shift or AltGr is kept alongside the
+        // modifiers.
+        None => {
+            let mut chars = key_part.chars();
+            match (chars.next(), chars.next()) {
+                (Some(ch), None) => Some(ch),
+                _ => x11_keysym_symbol(&key_part.to_lowercase()),
+            }
+            .and_then(|ch| char_to_chord(ch, options.layout, linux_keys))
+            .ok_or_else(|| unknown_key(key_part))?
+        }
+    };
+
+    let modifiers = if modifiers.is_empty() {
+        Vec::new()
+    } else {
+        modifiers.split('+').collect()
+    };
+    for modifier in modifiers {
+        match modifier.to_lowercase().as_str() {
             "super" => chord.super_key = true,
             "altgr" => chord.altgr = true,
             "ctrl" | "control" => chord.ctrl = true,
             "alt" => chord.alt = true,
             "shift" => chord.shift = true,
-            _ => return Err(format!("unknown modifier: {}", parts[i])),
+            _ => return Err(format!("unknown modifier: {}", modifier)),
         }
     }
 
     Ok(chord)
 }
 
-fn list_keys(keys: &HashMap<String, Keyboard>) {
-    let mut items: Vec<_> = keys.iter().collect();
+fn list_keys(
+    out: &mut dyn Write,
+    keys: &HashMap<String, Keyboard>,
+    pattern: Option<&str>,
+) -> io::Result<()> {
+    let pattern = pattern.map(str::to_lowercase);
+    let mut items: Vec<_> = keys
+        .iter()
+        .filter(|(name, _)| pattern.as_ref().is_none_or(|p| name.contains(p.as_str())))
+        .collect();
     items.sort_by_key(|(name, _)| *name);
 
     let margin = items.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
 
     for (name, key) in items {
-        println!("{:<width$} {:?}", name, key, width = margin);
+        writeln!(out, "{:<width$} {:?}", name, key, width = margin)?;
+    }
+    Ok(())
+}
+
+/// A hash of every key name and its code, which changes whenever a key is
+/// added, removed or renamed. FNV-1a, so it is the same on every build.
+fn keymap_hash(keys: &HashMap<String, Keyboard>) -> String {
+    let mut pairs: Vec<_> = keys.iter().map(|(name, key)| (name, key.code())).collect();
+    pairs.sort();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for (name, code) in pairs {
+        for byte in format!("{}={}\n", name, code).bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
     }
+    format!("{:016x}", hash)
 }
 
 fn init_linux_keys() -> HashMap<String, Keyboard> {
@@ -254,6 +1167,40 @@ fn init_linux_keys() -> HashMap<String, Keyboard> {
         ("comma", Keyboard::Key(Comma)),
         ("dot", Keyboard::Key(Dot)),
         ("slash", Keyboard::Key(Slash)),
+        ("102nd", Keyboard::Misc(Misc::ND102)),
+        ("ro", Keyboard::Misc(Misc::RO)),
+        ("yen", Keyboard::Misc(Misc::Yen)),
+        ("kpcomma", Keyboard::KeyPad(KeyPad::Comma)),
+        ("kpjpcomma", Keyboard::KeyPad(KeyPad::AltComma)),
+        ("zenkakuhankaku", Keyboard::Misc(Misc::ZenkakuHankaku)),
+        ("katakana", Keyboard::Misc(Misc::Katakana)),
+        ("hiragana", Keyboard::Misc(Misc::Hiragana)),
+        ("katakanahiragana", Keyboard::Misc(Misc::KatakanaHiragana)),
+        ("henkan", Keyboard::Misc(Misc::Henkan)),
+        ("muhenkan", Keyboard::Misc(Misc::Muhenkan)),
+        ("hangeul", Keyboard::Misc(Misc::Hangeul)),
+        ("hanja", Keyboard::Misc(Misc::Hanja)),
+        ("compose", Keyboard::Misc(Misc::Compose)),
+        ("kp0", Keyboard::KeyPad(KeyPad::_0)),
+        ("kp1", Keyboard::KeyPad(KeyPad::_1)),
+        ("kp2", Keyboard::KeyPad(KeyPad::_2)),
+        ("kp3", Keyboard::KeyPad(KeyPad::_3)),
+        ("kp4", Keyboard::KeyPad(KeyPad::_4)),
+        ("kp5", Keyboard::KeyPad(KeyPad::_5)),
+        ("kp6", Keyboard::KeyPad(KeyPad::_6)),
+        ("kp7", Keyboard::KeyPad(KeyPad::_7)),
+        ("kp8", Keyboard::KeyPad(KeyPad::_8)),
+        ("kp9", Keyboard::KeyPad(KeyPad::_9)),
+        ("kpasterisk", Keyboard::KeyPad(KeyPad::Asterisk)),
+        ("kpminus", Keyboard::KeyPad(KeyPad::Minus)),
+        ("kpplus", Keyboard::KeyPad(KeyPad::Plus)),
+        ("kpdot", Keyboard::KeyPad(KeyPad::Dot)),
+        ("kpenter", Keyboard::KeyPad(KeyPad::Enter)),
+        ("kpslash", Keyboard::KeyPad(KeyPad::Slash)),
+        ("kpequal", Keyboard::KeyPad(KeyPad::Equal)),
+        ("kpplusminus", Keyboard::KeyPad(KeyPad::PlusMinus)),
+        ("kpleftparen", Keyboard::KeyPad(KeyPad::LeftParen)),
+        ("kprightparen", Keyboard::KeyPad(KeyPad::RightParen)),
     ];
 
     key_mappings
@@ -262,203 +1209,4487 @@ fn init_linux_keys() -> HashMap<String, Keyboard> {
         .collect()
 }
 
-fn char_to_chord(ch: char, linux_keys: &HashMap<String, Keyboard>) -> Option<Chord> {
-    let (key_str, needs_shift) = match ch {
-        'a'..='z' => (ch.to_string(), false),
-        'A'..='Z' => (ch.to_lowercase().to_string(), true),
-        '0'..='9' => (ch.to_string(), false),
-        ' ' => ("space".to_string(), false),
-        '\n' => ("enter".to_string(), false),
-        '\t' => ("tab".to_string(), false),
-        '-' => ("minus".to_string(), false),
-        '=' => ("equal".to_string(), false),
-        '[' => ("leftbrace".to_string(), false),
-        ']' => ("rightbrace".to_string(), false),
-        ';' => ("semicolon".to_string(), false),
-        '\'' => ("apostrophe".to_string(), false),
-        '`' => ("grave".to_string(), false),
-        '\\' => ("backslash".to_string(), false),
-        ',' => ("comma".to_string(), false),
-        '.' => ("dot".to_string(), false),
-        '/' => ("slash".to_string(), false),
-        // Shifted symbols
-        '!' => ("1".to_string(), true),
-        '@' => ("2".to_string(), true),
-        '#' => ("3".to_string(), true),
-        '$' => ("4".to_string(), true),
-        '%' => ("5".to_string(), true),
-        '^' => ("6".to_string(), true),
-        '&' => ("7".to_string(), true),
-        '*' => ("8".to_string(), true),
-        '(' => ("9".to_string(), true),
-        ')' => ("0".to_string(), true),
-        '_' => ("minus".to_string(), true),
-        '+' => ("equal".to_string(), true),
-        '{' => ("leftbrace".to_string(), true),
-        '}' => ("rightbrace".to_string(), true),
-        ':' => ("semicolon".to_string(), true),
-        '"' => ("apostrophe".to_string(), true),
-        '~' => ("grave".to_string(), true),
-        '|' => ("backslash".to_string(), true),
-        '<' => ("comma".to_string(), true),
-        '>' => ("dot".to_string(), true),
-        '?' => ("slash".to_string(), true),
+/// Maps lowercased X11 keysym names, as used by xdotool, to our key names.
+/// Only consulted for names that are not in the key table themselves.
+fn x11_keysym_alias(name: &str) -> Option<&'static str> {
+    let alias = match name {
+        "prior" | "page_up" => "pageup",
+        "next" | "page_down" => "pagedown",
+        "kp_0" => "kp0",
+        "kp_1" => "kp1",
+        "kp_2" => "kp2",
+        "kp_3" => "kp3",
+        "kp_4" => "kp4",
+        "kp_5" => "kp5",
+        "kp_6" => "kp6",
+        "kp_7" => "kp7",
+        "kp_8" => "kp8",
+        "kp_9" => "kp9",
+        "kp_add" => "kpplus",
+        "kp_subtract" => "kpminus",
+        "kp_multiply" => "kpasterisk",
+        "kp_divide" => "kpslash",
+        "kp_decimal" => "kpdot",
+        "kp_separator" => "kpcomma",
+        "kp_enter" => "kpenter",
+        "kp_equal" => "kpequal",
+        "shift_l" => "leftshift",
+        "shift_r" => "rightshift",
+        "control_l" => "leftctrl",
+        "control_r" => "rightctrl",
+        "alt_l" => "leftalt",
+        "alt_r" | "iso_level3_shift" => "rightalt",
+        "super_l" | "meta_l" => "leftmeta",
+        "super_r" | "meta_r" => "rightmeta",
+        "caps_lock" => "capslock",
+        "num_lock" => "numlock",
+        "scroll_lock" => "scrolllock",
+        "sys_req" => "sysrq",
+        "menu" => "compose",
+        "bracketleft" => "leftbrace",
+        "bracketright" => "rightbrace",
+        "period" => "dot",
         _ => return None,
     };
 
-    let key = linux_keys.get(&key_str)?;
-    let mut chord = Chord::new(key.clone());
-    chord.shift = needs_shift;
+    Some(alias)
+}
+
+/// Maps lowercased X11 keysym names of symbols that have no key of their own,
+/// since they take Shift or AltGr on some layout, to the symbol.
+fn x11_keysym_symbol(name: &str) -> Option<char> {
+    let symbol = match name {
+        "exclam" => '!',
+        "at" => '@',
+        "numbersign" => '#',
+        "dollar" => '$',
+        "percent" => '%',
+        "asciicircum" => '^',
+        "ampersand" => '&',
+        "asterisk" => '*',
+        "parenleft" => '(',
+        "parenright" => ')',
+        "underscore" => '_',
+        "plus" => '+',
+        "braceleft" => '{',
+        "braceright" => '}',
+        "colon" => ':',
+        "quotedbl" => '"',
+        "asciitilde" => '~',
+        "bar" => '|',
+        "less" => '<',
+        "greater" => '>',
+        "question" => '?',
+        "sterling" => '£',
+        "degree" => '°',
+        "section" => '§',
+        "mu" => 'µ',
+        "currency" => '¤',
+        "eurosign" => '€',
+        _ => return None,
+    };
+
+    Some(symbol)
+}
+
+fn char_to_chord(
+    ch: char,
+    layout: Layout,
+    linux_keys: &HashMap<String, Keyboard>,
+) -> Option<Chord> {
+    let stroke = layout.stroke(ch)?;
+
+    let key = linux_keys.get(&stroke.key)?;
+    let mut chord = Chord::new(*key);
+    chord.shift = stroke.shift;
+    chord.altgr = stroke.altgr;
     Some(chord)
 }
 
-fn main() {
-    if let Err(e) = run() {
-        inform(&e);
-        process::exit(1);
+/// Keys for the C0 control characters that have an obvious equivalent.
+fn control_to_chord(ch: char, linux_keys: &HashMap<String, Keyboard>) -> Option<Chord> {
+    let key_str = match ch {
+        '\r' => "enter",
+        '\x08' => "backspace",
+        '\x1b' => "escape",
+        '\x7f' => "delete",
+        _ => return None,
+    };
+
+    linux_keys.get(key_str).map(|key| Chord::new(*key))
+}
+
+/// Pairs of modifiers that a real keyboard has no reason to hold together,
+/// which --strict warns about.
+const CONFLICTS: &[(Key, Key, &str)] = &[
+    (Key::LeftShift, Key::RightShift, "both Shift keys"),
+    (Key::LeftControl, Key::RightControl, "both Ctrl keys"),
+    (Key::LeftAlt, Key::RightAlt, "Alt and AltGr"),
+    (Key::LeftMeta, Key::RightMeta, "both Super keys"),
+];
+
+/// What is odd about pressing `chord` while `down` is held.
+fn modifier_conflicts(
+    down: &[Keyboard],
+    chord: &Chord,
+    modifiers: &ModifierKeys,
+) -> Vec<&'static str> {
+    let mut keys = down.to_vec();
+    keys.extend(chord.keys(modifiers));
+    let mut conflicts: Vec<&str> = CONFLICTS
+        .iter()
+        .filter(|(a, b, _)| keys.contains(&Keyboard::Key(*a)) && keys.contains(&Keyboard::Key(*b)))
+        .map(|(_, _, conflict)| *conflict)
+        .collect();
+    if chord.altgr && chord.alt && modifiers.alt == Side::Right {
+        conflicts.push("AltGr and Alt, which --alt-key right makes the same key");
     }
+    conflicts
 }
 
-fn run() -> Result<(), String> {
-    let version = env!("CARGO_PKG_VERSION");
-    let args: Vec<String> = env::args().collect();
+/// Which of a pair of modifier keys a chord presses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    Right,
+}
 
-    let linux_keys = init_linux_keys();
+impl Side {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "left" => Ok(Side::Left),
+            "right" => Ok(Side::Right),
+            _ => Err(format!("expected left or right: {}", s)),
+        }
+    }
 
-    // Parse command line arguments
-    for arg in args.iter().skip(1) {
-        match arg.as_str() {
-            "-h" | "--help" => {
-                usage();
-                return Ok(());
-            }
-            "--version" => {
-                println!("{}", version);
-                return Ok(());
-            }
-            "--list-keys" => {
-                list_keys(&linux_keys);
-                return Ok(());
-            }
-            _ => {
-                return Err(format!("unknown argument: {}", arg));
-            }
+    fn name(self) -> &'static str {
+        match self {
+            Side::Left => "left",
+            Side::Right => "right",
         }
     }
 
-    let mut keyboard = uinput::default()
-        .map_err(|e| format!("Failed to initialize uinput: {}", e))?
-        .name("dotool keyboard")
-        .map_err(|e| format!("Failed to set device name: {}", e))?
-        .event(uinput::event::Keyboard::All)
-        .map_err(|e| format!("Failed to set keyboard events: {}", e))?
-        .create()
-        .map_err(|e| format!("Failed to create keyboard device: {}", e))?;
+    fn pick(self, left: Key, right: Key) -> Key {
+        match self {
+            Side::Left => left,
+            Side::Right => right,
+        }
+    }
+}
 
-    let mut keydelay = Duration::from_millis(2);
-    let keyhold = Duration::from_millis(8);
-    let mut typedelay = Duration::from_millis(2);
-    let typehold = Duration::from_millis(8);
+/// The physical keys behind the super, ctrl, alt and shift chord modifiers.
+/// AltGr is always the right Alt key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ModifierKeys {
+    super_key: Side,
+    ctrl: Side,
+    alt: Side,
+    shift: Side,
+}
 
-    let stdin = io::stdin();
-    let reader = stdin.lock();
+impl ModifierKeys {
+    /// The keys for super, altgr, ctrl, alt and shift.
+    fn keys(&self) -> [Key; 5] {
+        [
+            self.super_key.pick(Key::LeftMeta, Key::RightMeta),
+            Key::RightAlt,
+            self.ctrl.pick(Key::LeftControl, Key::RightControl),
+            self.alt.pick(Key::LeftAlt, Key::RightAlt),
+            self.shift.pick(Key::LeftShift, Key::RightShift),
+        ]
+    }
 
-    for line in reader.lines() {
-        let line = line.map_err(|e| e.to_string())?;
-        let text = line.trim_start();
+    /// The key for a modifier given by the name chords use for it.
+    fn by_name(&self, name: &str) -> Option<Key> {
+        let [super_key, altgr, ctrl, alt, shift] = self.keys();
+        match name.to_lowercase().as_str() {
+            "super" => Some(super_key),
+            "altgr" => Some(altgr),
+            "ctrl" | "control" => Some(ctrl),
+            "alt" => Some(alt),
+            "shift" => Some(shift),
+            _ => None,
+        }
+    }
+}
 
-        if text.is_empty() {
-            continue;
+impl Default for ModifierKeys {
+    fn default() -> Self {
+        ModifierKeys {
+            super_key: Side::Left,
+            ctrl: Side::Left,
+            alt: Side::Left,
+            shift: Side::Left,
         }
+    }
+}
 
-        let mut parts = text.split_whitespace();
+/// A change `type` makes to its text before typing it, for generating test
+/// input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TypeTransform {
+    None,
+    Upper,
+    Lower,
+    Reverse,
+}
 
-        let op = match parts.next() {
-            Some(s) => s,
-            None => panic!("Invalid command"),
-        };
+impl TypeTransform {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(TypeTransform::None),
+            "upper" => Ok(TypeTransform::Upper),
+            "lower" => Ok(TypeTransform::Lower),
+            "reverse" => Ok(TypeTransform::Reverse),
+            _ => Err(format!("invalid type transform: {}", s)),
+        }
+    }
 
-        match op {
-            "key" => {
-                for field in parts {
-                    match parse_chord(field, &linux_keys) {
-                        Ok(chord) => {
-                            if let Err(e) = chord.key_down(&mut keyboard) {
-                                warn(&format!("key down error: {}", e));
-                            }
-                            thread::sleep(keyhold);
-                            if let Err(e) = chord.key_up(&mut keyboard) {
-                                warn(&format!("key up error: {}", e));
-                            }
-                            thread::sleep(keydelay);
-                        }
-                        Err(e) => warn(&e),
-                    }
-                }
-            }
-            "keydown" => {
-                for field in parts {
-                    match parse_chord(field, &linux_keys) {
-                        Ok(chord) => {
-                            if let Err(e) = chord.key_down(&mut keyboard) {
-                                warn(&format!("key down error: {}", e));
+    fn name(self) -> &'static str {
+        match self {
+            TypeTransform::None => "none",
+            TypeTransform::Upper => "upper",
+            TypeTransform::Lower => "lower",
+            TypeTransform::Reverse => "reverse",
+        }
+    }
+
+    fn apply(self, text: String) -> String {
+        match self {
+            TypeTransform::None => text,
+            TypeTransform::Upper => text.to_uppercase(),
+            TypeTransform::Lower => text.to_lowercase(),
+            TypeTransform::Reverse => text.chars().rev().collect(),
+        }
+    }
+}
+
+/// The bus the virtual keyboard says it is on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bus {
+    Usb,
+    Bluetooth,
+    Virtual,
+}
+
+impl Bus {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "usb" => Ok(Bus::Usb),
+            "bluetooth" => Ok(Bus::Bluetooth),
+            "virtual" => Ok(Bus::Virtual),
+            _ => Err(format!("invalid bus: {}", s)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Bus::Usb => "usb",
+            Bus::Bluetooth => "bluetooth",
+            Bus::Virtual => "virtual",
+        }
+    }
+
+    /// The kernel's BUS_* constant.
+    fn code(self) -> u16 {
+        match self {
+            Bus::Usb => 0x03,
+            Bus::Bluetooth => 0x05,
+            Bus::Virtual => 0x06,
+        }
+    }
+}
+
+/// How `type` enters characters the layout has no key for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnicodeMethod {
+    /// Warn and skip them.
+    None,
+    /// Hold Alt and type the decimal code point on the keypad.
+    AltNumpad,
+}
+
+impl UnicodeMethod {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(UnicodeMethod::None),
+            "alt-numpad" => Ok(UnicodeMethod::AltNumpad),
+            _ => Err(format!("invalid unicode method: {}", s)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            UnicodeMethod::None => "none",
+            UnicodeMethod::AltNumpad => "alt-numpad",
+        }
+    }
+}
+
+/// What `type` does with control characters other than newline and tab.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ControlChars {
+    /// Type the ones with an equivalent key, drop the rest.
+    Map,
+    /// Drop all of them.
+    Drop,
+    /// Type the ones with an equivalent key, warn about the rest.
+    Warn,
+}
+
+impl ControlChars {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "map" => Ok(ControlChars::Map),
+            "drop" => Ok(ControlChars::Drop),
+            "warn" => Ok(ControlChars::Warn),
+            _ => Err(format!("invalid control character handling: {}", s)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ControlChars::Map => "map",
+            ControlChars::Drop => "drop",
+            ControlChars::Warn => "warn",
+        }
+    }
+}
+
+/// How `type` emits a newline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Newline {
+    Enter,
+    ShiftEnter,
+    Skip,
+}
+
+impl Newline {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "enter" => Ok(Newline::Enter),
+            "shift-enter" => Ok(Newline::ShiftEnter),
+            "none" => Ok(Newline::Skip),
+            _ => Err(format!("invalid newline handling: {}", s)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Newline::Enter => "enter",
+            Newline::ShiftEnter => "shift-enter",
+            Newline::Skip => "none",
+        }
+    }
+}
+
+/// Settings taken from the command line or a `--profile` file.
+#[derive(Debug, Clone)]
+struct Options {
+    control_chars: ControlChars,
+    auto_shift: bool,
+    layout: Layout,
+    keydelay: Delay,
+    keyhold: Duration,
+    typedelay: Delay,
+    typehold: Duration,
+    /// typedelay after whitespace, when it differs.
+    worddelay: Option<Delay>,
+    /// Typing speed in characters per minute, overriding typedelay.
+    cpm: Option<f64>,
+    /// keyhold overrides for the `key` action, by key code, so that any name
+    /// of a key finds its entry.
+    hold_map: BTreeMap<u16, Duration>,
+    /// Device to read lock LEDs from, found automatically when unset.
+    led_device: Option<PathBuf>,
+    /// Lock keys to turn on before the first action.
+    initial_locks: Vec<Led>,
+    /// Undo what `initial_locks` changed at EOF.
+    restore_locks: bool,
+    wait_timeout: Duration,
+    /// Hold modifiers across runs of the same character in `type`.
+    coalesce_repeats: bool,
+    /// Type stdin as text instead of reading actions from it.
+    type_stdin: bool,
+    /// Replay stdin as binary input_event records.
+    replay_raw: bool,
+    /// Warn about chords that hold conflicting modifiers.
+    strict: bool,
+    /// Refuse the combinations in safe_block.
+    safe_mode: bool,
+    /// Chords for --safe-mode, where `default` stands for SAFE_BLOCK_DEFAULT.
+    safe_block: Vec<String>,
+    /// Tap the chords in braces in the text of `type`.
+    type_markup: bool,
+    /// Unescape backslashes in the text of `type`.
+    type_escapes: bool,
+    create_retries: u32,
+    /// Bus type for the created keyboard; left at 0 when unset.
+    bus: Option<Bus>,
+    /// Create the keyboard with only the keys in the key table.
+    minimal_caps: bool,
+    /// Attempts after the first for an event the device rejects.
+    key_retries: u32,
+    /// Lines read from stdin ahead of the action being run.
+    queue_depth: usize,
+    newline: Newline,
+    /// Wait for the new keyboard to show up before reading actions.
+    startup_delay: bool,
+    /// Tap a harmless key before the first action.
+    wake_key: bool,
+    normalize: Normalization,
+    /// Give up on a single device write after this long.
+    action_timeout: Option<Duration>,
+    /// Seed for delay ranges, so a run can be repeated exactly.
+    seed: Option<u64>,
+    /// The seed taken from the clock when `seed` is not set. It is not a
+    /// setting, so that a saved --show-config does not pin it.
+    clock_seed: Option<u64>,
+    /// Release a `keydown` chord that has not seen its `keyup` after this long.
+    max_hold: Option<Duration>,
+    /// Pause after a chord with Super or Alt, for the focus to move.
+    focus_settle: Duration,
+    /// Warn about actions that take longer than this.
+    warn_slow: Option<Duration>,
+    /// Print a summary of the session to stderr at EOF.
+    stats: bool,
+    /// Report details of actions to stderr.
+    verbose: bool,
+    /// Show how far through its text `type` is on stderr.
+    progress: bool,
+    /// Print `echo` messages to stdout.
+    echo: bool,
+    unicode_method: UnicodeMethod,
+    /// Command whose output `type` uses for characters the layout lacks.
+    char_hook: Option<String>,
+    type_transform: TypeTransform,
+    modifiers: ModifierKeys,
+    /// Write to this existing event device instead of creating a keyboard.
+    use_device: Option<PathBuf>,
+    /// The chords `seq` sends for each name, as a space separated list.
+    seqs: HashMap<String, String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            control_chars: ControlChars::Map,
+            auto_shift: true,
+            layout: Layout::Us,
+            keydelay: Delay::fixed(Duration::from_millis(2)),
+            keyhold: Duration::from_millis(8),
+            typedelay: Delay::fixed(Duration::from_millis(2)),
+            worddelay: None,
+            typehold: Duration::from_millis(8),
+            cpm: None,
+            hold_map: BTreeMap::new(),
+            led_device: None,
+            initial_locks: Vec::new(),
+            restore_locks: false,
+            wait_timeout: Duration::from_secs(5),
+            coalesce_repeats: false,
+            type_escapes: false,
+            type_markup: false,
+            strict: false,
+            safe_mode: false,
+            safe_block: vec!["default".to_string()],
+            type_stdin: false,
+            replay_raw: false,
+            create_retries: 0,
+            bus: None,
+            minimal_caps: false,
+            key_retries: 0,
+            queue_depth: 1024,
+            newline: Newline::Enter,
+            startup_delay: true,
+            wake_key: false,
+            normalize: Normalization::Nfc,
+            action_timeout: None,
+            seed: None,
+            clock_seed: None,
+            max_hold: None,
+            warn_slow: None,
+            focus_settle: Duration::ZERO,
+            stats: false,
+            verbose: false,
+            progress: false,
+            echo: false,
+            unicode_method: UnicodeMethod::None,
+            char_hook: None,
+            type_transform: TypeTransform::None,
+            modifiers: ModifierKeys::default(),
+            use_device: None,
+            seqs: BUILTIN_SEQS
+                .iter()
+                .map(|(name, chords)| (name.to_string(), chords.to_string()))
+                .collect(),
+        }
+    }
+}
+
+/// The sequences `seq` knows without a profile. A `[seq]` table in the
+/// profile adds to these or replaces them.
+const BUILTIN_SEQS: &[(&str, &str)] = &[
+    ("save", "ctrl+s"),
+    ("selectall", "ctrl+a"),
+    ("copy", "ctrl+c"),
+    ("cut", "ctrl+x"),
+    ("paste", "ctrl+v"),
+    ("undo", "ctrl+z"),
+    ("redo", "ctrl+shift+z"),
+];
+
+/// Settings accepted by `Options::set`, and whether each takes a value on the
+/// command line. Flags without a value are switched on by their presence.
+const SETTINGS: &[(&str, bool)] = &[
+    ("type-controls", true),
+    ("no-auto-shift", false),
+    ("layout", true),
+    ("keydelay", true),
+    ("keyhold", true),
+    ("typedelay", true),
+    ("typehold", true),
+    ("worddelay", true),
+    ("cpm", true),
+    ("wpm", true),
+    ("hold-map", true),
+    ("led-device", true),
+    ("initial-locks", true),
+    ("restore-locks", false),
+    ("wait-timeout", true),
+    ("coalesce-repeats", false),
+    ("type-escapes", false),
+    ("type-markup", false),
+    ("strict", false),
+    ("safe-mode", false),
+    ("safe-block", true),
+    ("type-stdin", false),
+    ("replay-raw", false),
+    ("create-retries", true),
+    ("bus", true),
+    ("minimal-caps", false),
+    ("key-retries", true),
+    ("queue-depth", true),
+    ("type-newline-as", true),
+    ("no-startup-delay", false),
+    ("wake-key", false),
+    ("normalize", true),
+    ("action-timeout", true),
+    ("seed", true),
+    ("max-hold", true),
+    ("warn-slow", true),
+    ("focus-settle", true),
+    ("stats", false),
+    ("verbose", false),
+    ("progress", false),
+    ("echo", false),
+    ("unicode-method", true),
+    ("char-hook", true),
+    ("type-transform", true),
+    ("super-key", true),
+    ("ctrl-key", true),
+    ("alt-key", true),
+    ("shift-key", true),
+    ("use-device", true),
+];
+
+impl Options {
+    /// Applies a setting by its flag name without the leading dashes, so
+    /// command line flags and profile keys share one implementation.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "type-controls" => self.control_chars = ControlChars::parse(value)?,
+            "no-auto-shift" => self.auto_shift = !parse_bool(name, value)?,
+            "layout" => self.layout = Layout::parse(value)?,
+            "keydelay" => self.keydelay = parse_delay(name, value)?,
+            "keyhold" => self.keyhold = parse_millis(name, value)?,
+            "typedelay" => self.typedelay = parse_delay(name, value)?,
+            "typehold" => self.typehold = parse_millis(name, value)?,
+            "worddelay" => self.worddelay = Some(parse_delay(name, value)?),
+            "cpm" => self.cpm = Some(parse_rate(name, value)?),
+            // The usual convention of five characters per word.
+            "wpm" => self.cpm = Some(parse_rate(name, value)? * 5.0),
+            "hold-map" => {
+                let linux_keys = init_linux_keys();
+                for entry in value.split(',').filter(|e| !e.trim().is_empty()) {
+                    let (key, ms) = entry
+                        .split_once('=')
+                        .ok_or_else(|| format!("invalid {} entry: {}", name, entry))?;
+                    let chord = parse_chord(key.trim(), &linux_keys, self)
+                        .map_err(|e| format!("invalid {} entry: {}", name, e))?;
+                    self.hold_map
+                        .insert(chord.key.code() as u16, parse_millis(name, ms)?);
+                }
+            }
+            "led-device" => self.led_device = Some(PathBuf::from(value)),
+            "initial-locks" => {
+                self.initial_locks = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(Led::parse)
+                    .collect::<Result<_, _>>()?;
+            }
+            "restore-locks" => self.restore_locks = parse_bool(name, value)?,
+            "use-device" => self.use_device = Some(PathBuf::from(value)),
+            "wait-timeout" => self.wait_timeout = parse_millis(name, value)?,
+            "coalesce-repeats" => self.coalesce_repeats = parse_bool(name, value)?,
+            "type-escapes" => self.type_escapes = parse_bool(name, value)?,
+            "type-markup" => self.type_markup = parse_bool(name, value)?,
+            "strict" => self.strict = parse_bool(name, value)?,
+            "safe-mode" => self.safe_mode = parse_bool(name, value)?,
+            "safe-block" => {
+                self.safe_block = value
+                    .split(',')
+                    .map(|entry| entry.trim().to_lowercase())
+                    .filter(|entry| !entry.is_empty())
+                    .collect();
+            }
+            "type-stdin" => self.type_stdin = parse_bool(name, value)?,
+            "replay-raw" => self.replay_raw = parse_bool(name, value)?,
+            "type-newline-as" => self.newline = Newline::parse(value)?,
+            "no-startup-delay" => self.startup_delay = !parse_bool(name, value)?,
+            "wake-key" => self.wake_key = parse_bool(name, value)?,
+            "normalize" => self.normalize = Normalization::parse(value)?,
+            "action-timeout" => self.action_timeout = Some(parse_millis(name, value)?),
+            "stats" => self.stats = parse_bool(name, value)?,
+            "verbose" => self.verbose = parse_bool(name, value)?,
+            "progress" => self.progress = parse_bool(name, value)?,
+            "echo" => self.echo = parse_bool(name, value)?,
+            "unicode-method" => self.unicode_method = UnicodeMethod::parse(value)?,
+            "char-hook" => self.char_hook = Some(value.to_string()),
+            "type-transform" => self.type_transform = TypeTransform::parse(value)?,
+            "super-key" => self.modifiers.super_key = Side::parse(value)?,
+            "ctrl-key" => self.modifiers.ctrl = Side::parse(value)?,
+            "alt-key" => self.modifiers.alt = Side::parse(value)?,
+            "shift-key" => self.modifiers.shift = Side::parse(value)?,
+            "max-hold" => self.max_hold = Some(parse_millis(name, value)?),
+            "warn-slow" => self.warn_slow = Some(parse_millis(name, value)?),
+            "focus-settle" => self.focus_settle = parse_millis(name, value)?,
+            "seed" => {
+                self.seed = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid value for {}: {}", name, value))?,
+                );
+            }
+            "create-retries" => {
+                self.create_retries = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for {}: {}", name, value))?;
+            }
+            "bus" => self.bus = Some(Bus::parse(value)?),
+            "minimal-caps" => self.minimal_caps = parse_bool(name, value)?,
+            "key-retries" => {
+                self.key_retries = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for {}: {}", name, value))?;
+            }
+            "queue-depth" => {
+                self.queue_depth = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for {}: {}", name, value))?;
+            }
+            _ if name.starts_with("seq.") => {
+                self.seqs
+                    .insert(name["seq.".len()..].to_lowercase(), value.to_string());
+            }
+            _ => return Err(format!("unknown setting: {}", name)),
+        }
+        Ok(())
+    }
+
+    /// typedelay after applying --cpm or --wpm, if given.
+    fn effective_typedelay(&self) -> Delay {
+        match self.cpm {
+            Some(cpm) => Delay::fixed(typedelay_for_cpm(cpm, self.typehold)),
+            None => self.typedelay,
+        }
+    }
+
+    /// Prints the settings in effect in profile syntax, so the output can be
+    /// saved and passed back with --profile.
+    fn show(&self, out: &mut dyn Write) -> io::Result<()> {
+        let ms = delay::format_millis;
+        let mut hold_map: Vec<String> = self
+            .hold_map
+            .iter()
+            .map(|(code, hold)| format!("{}={}", key_codes()[code].0, ms(*hold)))
+            .collect();
+        hold_map.sort();
+
+        writeln!(out, "# backend: uinput")?;
+        writeln!(out, "# device name: {}", DEVICE_NAME)?;
+        // Ranges are not numbers, so they need quoting.
+        let delay = |d: Delay| {
+            if d.is_fixed() {
+                d.to_string()
+            } else {
+                quote(d)
+            }
+        };
+        writeln!(out, "keydelay = {}", delay(self.keydelay))?;
+        writeln!(out, "keyhold = {}", ms(self.keyhold))?;
+        writeln!(out, "typedelay = {}", delay(self.effective_typedelay()))?;
+        writeln!(out, "typehold = {}", ms(self.typehold))?;
+        if let Some(worddelay) = self.worddelay {
+            writeln!(out, "worddelay = {}", delay(worddelay))?;
+        }
+        writeln!(out, "layout = {}", quote(self.layout.name()))?;
+        writeln!(out, "no-auto-shift = {}", !self.auto_shift)?;
+        writeln!(out, "type-controls = {}", quote(self.control_chars.name()))?;
+        writeln!(out, "type-newline-as = {}", quote(self.newline.name()))?;
+        writeln!(out, "normalize = {}", quote(self.normalize.name()))?;
+        writeln!(
+            out,
+            "unicode-method = {}",
+            quote(self.unicode_method.name())
+        )?;
+        if let Some(hook) = &self.char_hook {
+            writeln!(out, "char-hook = {}", quote(hook))?;
+        }
+        writeln!(
+            out,
+            "type-transform = {}",
+            quote(self.type_transform.name())
+        )?;
+        writeln!(
+            out,
+            "super-key = {}",
+            quote(self.modifiers.super_key.name())
+        )?;
+        writeln!(out, "ctrl-key = {}", quote(self.modifiers.ctrl.name()))?;
+        writeln!(out, "alt-key = {}", quote(self.modifiers.alt.name()))?;
+        writeln!(out, "shift-key = {}", quote(self.modifiers.shift.name()))?;
+        writeln!(out, "coalesce-repeats = {}", self.coalesce_repeats)?;
+        writeln!(out, "type-escapes = {}", self.type_escapes)?;
+        writeln!(out, "type-markup = {}", self.type_markup)?;
+        writeln!(out, "strict = {}", self.strict)?;
+        writeln!(out, "safe-mode = {}", self.safe_mode)?;
+        writeln!(out, "safe-block = {}", quote(self.safe_block.join(",")))?;
+        writeln!(out, "type-stdin = {}", self.type_stdin)?;
+        writeln!(out, "replay-raw = {}", self.replay_raw)?;
+        writeln!(out, "hold-map = {}", quote(hold_map.join(",")))?;
+        if let Some(path) = &self.led_device {
+            writeln!(out, "led-device = {}", quote(path.display()))?;
+        }
+        let locks: Vec<&str> = self.initial_locks.iter().map(|led| led.name()).collect();
+        writeln!(out, "initial-locks = {}", quote(locks.join(",")))?;
+        writeln!(out, "restore-locks = {}", self.restore_locks)?;
+        if let Some(path) = &self.use_device {
+            writeln!(out, "use-device = {}", quote(path.display()))?;
+        }
+        writeln!(out, "wait-timeout = {}", ms(self.wait_timeout))?;
+        writeln!(out, "focus-settle = {}", ms(self.focus_settle))?;
+        if let Some(timeout) = self.action_timeout {
+            writeln!(out, "action-timeout = {}", ms(timeout))?;
+        }
+        if let Some(hold) = self.max_hold {
+            writeln!(out, "max-hold = {}", ms(hold))?;
+        }
+        if let Some(slow) = self.warn_slow {
+            writeln!(out, "warn-slow = {}", ms(slow))?;
+        }
+        if let Some(seed) = self.seed {
+            writeln!(out, "seed = {}", seed)?;
+        } else if let Some(seed) = self.clock_seed {
+            writeln!(out, "# seed = {} (from the clock)", seed)?;
+        }
+
+        writeln!(out, "create-retries = {}", self.create_retries)?;
+        if let Some(bus) = self.bus {
+            writeln!(out, "bus = {}", quote(bus.name()))?;
+        }
+        writeln!(out, "minimal-caps = {}", self.minimal_caps)?;
+        writeln!(out, "key-retries = {}", self.key_retries)?;
+        writeln!(out, "queue-depth = {}", self.queue_depth)?;
+        writeln!(out, "no-startup-delay = {}", !self.startup_delay)?;
+        writeln!(out, "wake-key = {}", self.wake_key)?;
+        writeln!(out, "stats = {}", self.stats)?;
+        writeln!(out, "verbose = {}", self.verbose)?;
+        writeln!(out, "progress = {}", self.progress)?;
+        writeln!(out, "echo = {}", self.echo)?;
+
+        // Tables come last, since every key after a header belongs to it.
+        let mut seqs: Vec<_> = self.seqs.iter().collect();
+        seqs.sort();
+        writeln!(out, "\n[seq]")?;
+        for (name, chords) in seqs {
+            writeln!(out, "{} = {}", quote_key(name), quote(chords))?;
+        }
+        Ok(())
+    }
+
+    fn load_profile(&mut self, path: &str) -> Result<(), String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("cannot read profile {}: {}", path, e))?;
+        let settings = profile::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+        for (name, value) in settings {
+            self.set(&name, &value)
+                .map_err(|e| format!("{}: {}", path, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the version and, with `verbose`, what a bug report should mention
+/// about the build and the system it runs on.
+fn print_version(out: &mut dyn Write, version: &str, verbose: bool) -> io::Result<()> {
+    writeln!(out, "{}", version)?;
+    if !verbose {
+        return Ok(());
+    }
+
+    let (uinput, protocol) = match fs::OpenOptions::new().write(true).open("/dev/uinput") {
+        Ok(file) => (
+            "writable".to_string(),
+            uinput_version(&file).map_or_else(
+                |e| format!("unknown ({}), from before Linux 3.15", e),
+                |version| version.to_string(),
+            ),
+        ),
+        Err(e) => (format!("not writable: {}", e), "unknown".to_string()),
+    };
+    let kernel = fs::read_to_string("/proc/sys/kernel/osrelease").map_or_else(
+        |e| format!("unknown ({})", e),
+        |release| release.trim().to_string(),
+    );
+    let layouts: Vec<&str> = Layout::ALL.iter().map(|layout| layout.name()).collect();
+    writeln!(out, "backends: uinput")?;
+    writeln!(out, "kernel: {}", kernel)?;
+    writeln!(out, "/dev/uinput: {}", uinput)?;
+    writeln!(out, "uinput version: {}", protocol)?;
+    writeln!(out, "layouts: {}", layouts.join(", "))?;
+    writeln!(out, "optional features: none")
+}
+
+/// The uinput protocol version, from UI_GET_VERSION. Everything dotool does
+/// works with any version, so this is only reported.
+fn uinput_version(file: &fs::File) -> io::Result<u32> {
+    // _IOR('U', 45, unsigned int)
+    const UI_GET_VERSION: libc::c_ulong = 0x8004_552d;
+    let mut version: libc::c_uint = 0;
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), UI_GET_VERSION, &mut version) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(version)
+}
+
+/// Runs `print` against a buffered stdout. A reader that goes away early, as
+/// with `dotool --list-keys | head`, ends the output quietly instead of
+/// panicking on the broken pipe.
+fn write_stdout(print: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> Result<(), String> {
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    match print(&mut out).and_then(|()| out.flush()) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.map_err(|e| format!("failed to write to stdout: {}", e)),
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        inform(&e);
+        process::exit(1);
+    }
+}
+
+/// The text after an action's keyword, less only the one whitespace character
+/// that separates them, so `type` reproduces leading and trailing spaces.
+fn action_text<'a>(text: &'a str, op: &str) -> Option<&'a str> {
+    let mut rest = text[op.len()..].chars();
+    rest.next()?;
+    Some(rest.as_str())
+}
+
+/// Splits action arguments at whitespace, except inside double quotes, which
+/// keep spaces and can hold \" and \\. Quotes can join a word, as in
+/// name="a b". For actions with structured arguments; `type` keeps its text.
+fn tokenize(mut text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    while let Some((token, rest)) = next_token(text)? {
+        tokens.push(token);
+        text = rest;
+    }
+    Ok(tokens)
+}
+
+/// The first argument of `text`, as tokenize splits it, and the text after
+/// it, for actions that end in another action.
+fn next_token(text: &str) -> Result<Option<(String, &str)>, String> {
+    let text = text.trim_start();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let mut token = String::new();
+    let mut quoted = false;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '"' => quoted = !quoted,
+            '\\' if quoted && matches!(chars.peek(), Some((_, '"' | '\\'))) => {
+                token.push(chars.next().unwrap().1)
+            }
+            ch if ch.is_whitespace() && !quoted => return Ok(Some((token, &text[i..]))),
+            ch => token.push(ch),
+        }
+    }
+    if quoted {
+        return Err(format!("unterminated quote in: {}", text));
+    }
+    Ok(Some((token, "")))
+}
+
+/// What `repeat-until` waits for.
+#[derive(Debug, PartialEq)]
+enum Condition {
+    /// The path exists, or with false, does not.
+    File(PathBuf, bool),
+    Led(Led, bool),
+}
+
+impl Condition {
+    /// Reads a condition from the start of `text`, returning the rest.
+    fn parse(text: &str) -> Result<(Self, &str), String> {
+        let missing = || format!("invalid condition: {}", text.trim());
+        let (kind, rest) = next_token(text)?.ok_or_else(missing)?;
+        let (arg, rest) = next_token(rest)?.ok_or_else(missing)?;
+        match kind.as_str() {
+            "file" => Ok((Condition::File(arg.into(), true), rest)),
+            "nofile" => Ok((Condition::File(arg.into(), false), rest)),
+            "led" => {
+                let (state, rest) = next_token(rest)?.ok_or_else(missing)?;
+                Ok((
+                    Condition::Led(Led::parse(&arg)?, parse_on_off(&state)?),
+                    rest,
+                ))
+            }
+            _ => Err(format!("unknown condition: {}", kind)),
+        }
+    }
+}
+
+/// A piece of the text of `type` under --type-markup.
+#[derive(Debug, PartialEq)]
+enum Markup<'a> {
+    Text(String),
+    /// The chords between a pair of braces.
+    Chords(&'a str),
+}
+
+/// Splits `text` into literal text and `{CHORD...}` tokens, with `{{` for a
+/// literal brace.
+fn parse_markup(text: &str) -> Result<Vec<Markup<'_>>, String> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut rest = text;
+    while let Some(i) = rest.find('{') {
+        literal.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('{') {
+            literal.push('{');
+            rest = after;
+            continue;
+        }
+        let end = rest
+            .find('}')
+            .ok_or_else(|| format!("unterminated {{ in type: {}", text))?;
+        if !literal.is_empty() {
+            pieces.push(Markup::Text(std::mem::take(&mut literal)));
+        }
+        pieces.push(Markup::Chords(&rest[..end]));
+        rest = &rest[end + 1..];
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        pieces.push(Markup::Text(literal));
+    }
+    Ok(pieces)
+}
+
+/// Drops the backslash in front of every escaped character, for
+/// --type-escapes. A lone backslash at the end stays.
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => out.push(chars.next().unwrap_or('\\')),
+            ch => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Redraws the --progress line of `type` when the percentage changes.
+fn show_progress(done: usize, total: usize, shown: &mut Option<usize>) {
+    let percent = done * 100 / total.max(1);
+    if *shown != Some(percent) {
+        eprint!("\rdotool: typing {}%", percent);
+        *shown = Some(percent);
+    }
+}
+
+/// Interprets action lines and drives a `KeySink` with the resulting events.
+struct Executor<S: KeySink> {
+    device: Counting<S>,
+    linux_keys: HashMap<String, Keyboard>,
+    options: Options,
+    keydelay: Delay,
+    keyhold: Duration,
+    typedelay: Delay,
+    typehold: Duration,
+    worddelay: Option<Delay>,
+    rng: Rng,
+    /// Chords held by `keydown` under --max-hold, with when to let them go.
+    held: Vec<(String, Chord, Instant)>,
+    /// Keys pressed by `keydown` and not yet released by `keyup`.
+    down: Vec<Keyboard>,
+    /// The keys each open `with` block pressed, innermost last.
+    blocks: Vec<Vec<Keyboard>>,
+    /// Actions put off by `at`, soonest first, with when to run them.
+    scheduled: Vec<(Instant, String)>,
+    /// Send events without waiting between them, for --compare.
+    dry_run: bool,
+    /// Characters `type` and `typefile` have sent, for --stats.
+    typed: usize,
+    /// The characters the last `type` had no key for, each once.
+    dropped: Vec<char>,
+    /// Actions that reached --warn-slow, and the line and time of the
+    /// slowest, for --stats.
+    slow: usize,
+    slowest: Option<(usize, Duration)>,
+    /// When `checkdeadline` starts to complain, as set by `deadline`.
+    deadline: Option<Instant>,
+    /// Why the script has to stop, when an action stopped it.
+    aborted: Option<String>,
+}
+
+impl<S: KeySink> Executor<S> {
+    /// Fails when --safe-mode is on and its blocklist is invalid, rather than
+    /// running without it.
+    fn new(
+        device: S,
+        linux_keys: HashMap<String, Keyboard>,
+        options: Options,
+    ) -> Result<Self, String> {
+        let blocked = if options.safe_mode {
+            safe_blocklist(&options, &linux_keys)?
+        } else {
+            Vec::new()
+        };
+        Ok(Executor {
+            device: Counting {
+                blocked,
+                ..Counting::new(device, options.key_retries)
+            },
+            linux_keys,
+            keydelay: options.keydelay,
+            keyhold: options.keyhold,
+            typedelay: options.effective_typedelay(),
+            typehold: options.typehold,
+            worddelay: options.worddelay,
+            rng: Rng::new(
+                options
+                    .seed
+                    .or(options.clock_seed)
+                    .unwrap_or_else(delay::seed_from_time),
+            ),
+            held: Vec::new(),
+            down: Vec::new(),
+            blocks: Vec::new(),
+            scheduled: Vec::new(),
+            dry_run: false,
+            typed: 0,
+            dropped: Vec::new(),
+            slow: 0,
+            slowest: None,
+            deadline: None,
+            aborted: None,
+            options,
+        })
+    }
+
+    fn execute(&mut self, line: &str) {
+        let text = line.trim_start();
+
+        if text.is_empty() {
+            return;
+        }
+
+        let mut parts = text.split_whitespace();
+
+        let Some(op) = parts.next() else {
+            return;
+        };
+
+        match op {
+            "key" => {
+                for field in parts {
+                    self.tap(field);
+                }
+            }
+            "seq" => {
+                for name in parts {
+                    match self.options.seqs.get(&name.to_lowercase()) {
+                        Some(chords) => {
+                            for field in chords.clone().split_whitespace() {
+                                self.tap(field);
                             }
-                            thread::sleep(keydelay);
                         }
-                        Err(e) => warn(&e),
+                        None => warn(&format!("unknown sequence: {}", name)),
+                    }
+                }
+            }
+            "at" => {
+                let delay = parts.next().map(|ms| parse_millis(op, ms));
+                match (delay, parts.remainder()) {
+                    (Some(Ok(delay)), Some(action)) => {
+                        let deadline = Instant::now() + delay;
+                        let i = self.scheduled.partition_point(|(due, _)| *due <= deadline);
+                        self.scheduled.insert(i, (deadline, action.to_string()));
+                    }
+                    (Some(Err(e)), _) => warn(&e),
+                    _ => warn("at needs a delay and an action"),
+                }
+            }
+            "deadline" => match parts.next().map(|ms| parse_millis(op, ms)) {
+                Some(Ok(ms)) => self.deadline = Some(Instant::now() + ms),
+                Some(Err(e)) => warn(&e),
+                None => warn("deadline needs a value"),
+            },
+            "checkdeadline" => self.check_deadline(),
+            "requirekeymap" => match parts.next() {
+                Some(hash) => {
+                    let actual = keymap_hash(&self.linux_keys);
+                    if !hash.eq_ignore_ascii_case(&actual) {
+                        self.fail(format!(
+                            "script needs key table {}, this dotool has {}",
+                            hash, actual
+                        ));
+                    }
+                }
+                None => warn("requirekeymap needs a hash"),
+            },
+            "ifenv" | "ifset" => match self.condition_met(op, parts.remainder().unwrap_or("")) {
+                Ok(Some(action)) => self.execute(action),
+                Ok(None) => {}
+                Err(e) => warn(&e),
+            },
+            "sequence" => {
+                for entry in parts {
+                    let hold = entry
+                        .rsplit_once(':')
+                        .and_then(|(field, ms)| Some((field, parse_millis(op, ms).ok()?)));
+                    match hold {
+                        Some((field, hold)) => self.tap_for(field, hold),
+                        None => warn(&format!("invalid sequence entry: {}", entry)),
                     }
                 }
             }
+            "keydown" => {
+                for field in parts {
+                    self.keydown(field);
+                }
+            }
             "keyup" => {
                 for field in parts {
-                    match parse_chord(field, &linux_keys) {
-                        Ok(chord) => {
-                            if let Err(e) = chord.key_up(&mut keyboard) {
-                                warn(&format!("key up error: {}", e));
-                            }
-                            thread::sleep(keydelay);
+                    self.keyup(field);
+                }
+            }
+            "with" => {
+                let before = self.down.len();
+                for field in parts {
+                    self.keydown(field);
+                }
+                self.blocks.push(self.down[before..].to_vec());
+            }
+            "end" => {
+                if !self.end_block() {
+                    warn("end without with");
+                }
+            }
+            "select" => {
+                let mut args: Vec<&str> = parts.collect();
+                let unit = match args.first() {
+                    Some(&unit @ ("char" | "word" | "line")) => {
+                        args.remove(0);
+                        unit
+                    }
+                    _ => "char",
+                };
+                let (direction, count) = match args.as_slice() {
+                    [direction] => (*direction, Ok(1)),
+                    [direction, count] => (*direction, count.parse::<usize>()),
+                    _ => return warn(&format!("invalid select: {}", text)),
+                };
+                let Ok(count) = count else {
+                    return warn(&format!("invalid select count: {}", text));
+                };
+                let key = match (unit, direction) {
+                    ("line", "left") => "home",
+                    ("line", "right") => "end",
+                    (_, "left" | "right" | "up" | "down") => direction,
+                    _ => return warn(&format!("invalid select direction: {}", direction)),
+                };
+                let modifiers: &[&str] = if unit == "word" {
+                    &["ctrl", "shift"]
+                } else {
+                    &["shift"]
+                };
+                self.select(modifiers, key, count);
+            }
+            "press" | "release" => {
+                for name in parts {
+                    let Some(key) = lookup_key(name, &self.linux_keys) else {
+                        warn(&unknown_key(name));
+                        continue;
+                    };
+                    if !self.device.supports(&key) {
+                        warn(&format!("{}: {:?} is not enabled on the device", name, key));
+                        continue;
+                    }
+                    let result = if op == "press" {
+                        self.device.press(&key)
+                    } else {
+                        self.device.release(&key)
+                    };
+                    if let Err(e) = result {
+                        warn(&format!("{} error: {}", op, e));
+                    }
+                }
+            }
+            "burst" => {
+                let args: Vec<&str> = parts.collect();
+                let [name, count, interval] = args.as_slice() else {
+                    return warn(&format!("invalid burst: {}", text));
+                };
+                let Some(key) = lookup_key(name, &self.linux_keys) else {
+                    return warn(&unknown_key(name));
+                };
+                if !self.device.supports(&key) {
+                    return warn(&format!("{}: {:?} is not enabled on the device", name, key));
+                }
+                let count = match count.parse::<usize>() {
+                    Ok(count) if count > 0 => count,
+                    _ => return warn(&format!("invalid burst count: {}", count)),
+                };
+                let interval = match parse_millis(op, interval) {
+                    Ok(interval) => interval,
+                    Err(e) => return warn(&e),
+                };
+                if let Err(e) = self.burst(name, key, count, interval) {
+                    warn(&format!("burst error: {}", e));
+                }
+                self.pause(self.keydelay);
+            }
+            "roll" => {
+                let mut names: Vec<&str> = parts.collect();
+                let nest = names.first() == Some(&"-nest");
+                if nest {
+                    names.remove(0);
+                }
+                let mut keys = Vec::new();
+                for name in names {
+                    match lookup_key(name, &self.linux_keys) {
+                        Some(key) if self.device.supports(&key) => keys.push(key),
+                        Some(key) => {
+                            warn(&format!("{}: {:?} is not enabled on the device", name, key))
                         }
-                        Err(e) => warn(&e),
+                        None => warn(&unknown_key(name)),
+                    }
+                }
+                if let Err(e) = self.roll(&keys, nest) {
+                    warn(&format!("roll error: {}", e));
+                }
+                self.pause(self.keydelay);
+            }
+            "raw" => {
+                let args: Vec<&str> = parts.collect();
+                let [name, value] = args.as_slice() else {
+                    warn(&format!("invalid raw: {}", text));
+                    return;
+                };
+                let Some(key) = lookup_key(name, &self.linux_keys) else {
+                    warn(&unknown_key(name));
+                    return;
+                };
+                if !self.device.supports(&key) {
+                    warn(&format!("{}: {:?} is not enabled on the device", name, key));
+                    return;
+                }
+                let result = match *value {
+                    "0" => self.device.release(&key),
+                    "1" => self.device.press(&key),
+                    "2" => self.device.repeat(&key),
+                    _ => {
+                        warn(&format!("raw value must be 0, 1 or 2: {}", value));
+                        return;
                     }
+                };
+                if let Err(e) = result.and_then(|()| self.device.synchronize()) {
+                    warn(&format!("raw error: {}", e));
+                }
+            }
+            "sync" => {
+                if let Err(e) = self.device.synchronize() {
+                    warn(&format!("sync error: {}", e));
                 }
             }
             "keydelay" => match parts.remainder() {
-                Some(s) => match s.trim().parse::<f64>() {
-                    Ok(d) => keydelay = Duration::from_millis(d as u64),
-                    Err(_) => warn(&format!("invalid delay: {}", text)),
+                Some(s) => match Delay::parse(s) {
+                    Some(d) => self.keydelay = d,
+                    None => warn(&format!("invalid delay: {}{}", text, comma_hint(s))),
                 },
-                None => panic!("Delay missing"),
+                None => warn("keydelay needs a value"),
             },
-            "type" => match parts.remainder() {
-                Some(s) => {
-                    for ch in s.chars() {
-                        if let Some(chord) = char_to_chord(ch, &linux_keys) {
-                            if let Err(e) = chord.key_down(&mut keyboard) {
-                                warn(&format!("type error: {}", e));
-                                continue;
-                            }
-                            thread::sleep(typehold);
-                            if let Err(e) = chord.key_up(&mut keyboard) {
-                                warn(&format!("type error: {}", e));
-                            }
-                            thread::sleep(typedelay);
-                        } else {
-                            warn(&format!("cannot type character: {}", ch));
+            "keyhold" | "typehold" => match parts.next().map(|value| parse_millis(op, value)) {
+                Some(Ok(hold)) if op == "keyhold" => self.keyhold = hold,
+                Some(Ok(hold)) => self.typehold = hold,
+                Some(Err(e)) => warn(&e),
+                None => warn(&format!("{} needs a value", op)),
+            },
+            // --compare writes its diff to stdout, and has no one to tell.
+            "echo" if self.dry_run => {}
+            "echo" => {
+                let message = action_text(text, op).unwrap_or("");
+                if !self.options.echo {
+                    inform(message);
+                } else if let Err(e) = writeln!(io::stdout(), "{}", message) {
+                    warn(&format!("cannot echo: {}", e));
+                }
+            }
+            "type" => match action_text(text, op) {
+                Some(s) if self.options.type_markup => match parse_markup(s) {
+                    Ok(pieces) => self.type_markup(pieces),
+                    Err(e) => warn(&e),
+                },
+                Some(s) if self.options.type_escapes => self.type_text(&unescape(s)),
+                Some(s) => self.type_text(s),
+                None => warn("type needs text"),
+            },
+            "typefile" => match parts.remainder().map(str::trim) {
+                Some(path) => {
+                    let path = match tokenize(path) {
+                        Ok(tokens) if path.starts_with('"') && tokens.len() == 1 => {
+                            tokens[0].clone()
+                        }
+                        Ok(_) => path.to_string(),
+                        Err(e) => return warn(&e),
+                    };
+                    match fs::read(&path) {
+                        Ok(bytes) => {
+                            let text = String::from_utf8_lossy(&bytes).replace("\r\n", "\n");
+                            self.type_text(&text);
                         }
+                        Err(e) => warn(&format!("cannot read {}: {}", path, e)),
                     }
                 }
-                None => panic!("Missing string to type"),
+                None => warn("typefile needs a path"),
             },
             "typedelay" => match parts.remainder() {
-                Some(s) => match s.trim().parse::<f64>() {
-                    Ok(d) => typedelay = Duration::from_millis(d as u64),
-                    Err(_) => warn(&format!("invalid delay: {}", text)),
+                Some(s) => match Delay::parse(s) {
+                    Some(d) => self.typedelay = d,
+                    None => warn(&format!("invalid delay: {}{}", text, comma_hint(s))),
                 },
-                None => panic!("Missing typedelay arguments"),
+                None => warn("typedelay needs a value"),
+            },
+            "defaults" => self.reset_timing(),
+            "worddelay" => match parts.remainder() {
+                Some(s) => match Delay::parse(s) {
+                    Some(d) => self.worddelay = Some(d),
+                    None => warn(&format!("invalid delay: {}{}", text, comma_hint(s))),
+                },
+                None => warn("worddelay needs a value"),
+            },
+            "layout" => match parts.next().map(Layout::parse) {
+                Some(Ok(layout)) => self.options.layout = layout,
+                Some(Err(e)) => warn(&e),
+                None => warn("layout needs a name"),
+            },
+            "waitled" => {
+                let args: Vec<&str> = parts.collect();
+                match args.as_slice() {
+                    [led, state] => match (Led::parse(led), parse_on_off(state)) {
+                        (Ok(led), Ok(on)) => self.wait_led(led, on),
+                        (Err(e), _) | (_, Err(e)) => warn(&e),
+                    },
+                    _ => warn(&format!("invalid waitled: {}", text)),
+                }
+            }
+            "waitkey" => match parts.next() {
+                Some(name) => match lookup_key(name, &self.linux_keys) {
+                    Some(key) => self.wait_key(name, key),
+                    None => warn(&unknown_key(name)),
+                },
+                None => warn("waitkey needs a key"),
+            },
+            "repeat-until" => match parts.remainder().map(Condition::parse) {
+                Some(Ok((condition, action))) if !action.trim().is_empty() => {
+                    self.repeat_until(&condition, action.trim())
+                }
+                Some(Err(e)) => warn(&e),
+                _ => warn("repeat-until needs a condition and an action"),
             },
-            _ => panic!("Unknown operation"),
+            "caps" | "num" | "scroll" => {
+                let led = Led::parse(op).unwrap();
+                match parts.next().map(parse_on_off) {
+                    Some(Ok(on)) => self.set_lock(led, on),
+                    Some(Err(e)) => warn(&e),
+                    None => warn(&format!("{} needs on or off", op)),
+                }
+            }
+            _ => warn(&format!("unknown action: {}", op)),
         }
     }
 
-    Ok(())
+    /// Parses a chord and checks that the device can emit all of its keys, so
+    /// a bad chord fails as a whole instead of halfway through its events.
+    fn chord(&self, chord_str: &str) -> Result<Chord, String> {
+        let chord = parse_chord(chord_str, &self.linux_keys, &self.options)?;
+        if let Some(key) = chord
+            .keys(&self.options.modifiers)
+            .iter()
+            .find(|key| !self.device.supports(key))
+        {
+            return Err(format!(
+                "{}: {:?} is not enabled on the device",
+                chord_str, key
+            ));
+        }
+        Ok(chord)
+    }
+
+    /// Whether `condition` holds now.
+    fn holds(&self, condition: &Condition) -> Result<bool, String> {
+        match condition {
+            Condition::File(path, exists) => Ok(path.exists() == *exists),
+            Condition::Led(led, on) => {
+                let path = self
+                    .led_device(*led)
+                    .map_err(|e| format!("cannot read LED state: {}", e))?;
+                evdev::led_on(&path, *led)
+                    .map(|state| state == *on)
+                    .map_err(|e| format!("cannot read LED state from {}: {}", path.display(), e))
+            }
+        }
+    }
+
+    /// Runs `action` until `condition` holds, checking before every run, for
+    /// at most wait-timeout.
+    fn repeat_until(&mut self, condition: &Condition, action: &str) {
+        let deadline = Instant::now() + self.options.wait_timeout;
+        let mut runs = 0;
+        loop {
+            match self.holds(condition) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => return warn(&e),
+            }
+            // Nothing changes without real time passing.
+            if self.dry_run && runs > 0 {
+                return;
+            }
+            if Instant::now() >= deadline {
+                return warn(&format!("repeat-until timed out after {} runs", runs));
+            }
+            self.execute(action);
+            runs += 1;
+            self.sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn led_device(&self, led: Led) -> io::Result<PathBuf> {
+        match &self.options.led_device {
+            Some(path) => Ok(path.clone()),
+            None => evdev::find_led_device(led),
+        }
+    }
+
+    /// Waits for someone to press `key` on a real keyboard, up to wait-timeout.
+    fn wait_key(&self, name: &str, key: Keyboard) {
+        if self.dry_run {
+            return;
+        }
+        // Our own keyboard cannot be where the press comes from.
+        let mut skip = evdev::nodes_named(DEVICE_NAME).unwrap_or_default();
+        skip.extend(self.options.use_device.clone());
+        match evdev::wait_for_key(key.code() as u16, self.options.wait_timeout, &skip) {
+            Ok(true) => {}
+            Ok(false) => warn(&format!("timed out waiting for {} to be pressed", name)),
+            Err(e) => warn(&format!("cannot wait for {}: {}", name, e)),
+        }
+    }
+
+    /// Polls the lock LED until it is in the wanted state or wait-timeout passes.
+    fn wait_led(&self, led: Led, on: bool) {
+        if self.dry_run {
+            return;
+        }
+        let path = match self.led_device(led) {
+            Ok(path) => path,
+            Err(e) => {
+                warn(&format!("cannot read LED state: {}", e));
+                return;
+            }
+        };
+
+        let deadline = Instant::now() + self.options.wait_timeout;
+        loop {
+            match evdev::led_on(&path, led) {
+                Ok(state) if state == on => return,
+                Ok(_) => {}
+                Err(e) => {
+                    warn(&format!(
+                        "cannot read LED state from {}: {}",
+                        path.display(),
+                        e
+                    ));
+                    return;
+                }
+            }
+            if Instant::now() >= deadline {
+                warn(&format!(
+                    "timed out waiting for {} lock to turn {}",
+                    led.name(),
+                    if on { "on" } else { "off" }
+                ));
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Presses the lock key only if its LED says it is not already in the
+    /// wanted state, then waits for the LED to confirm the change.
+    fn set_lock(&mut self, led: Led, on: bool) {
+        let state = self
+            .led_device(led)
+            .and_then(|path| evdev::led_on(&path, led));
+        match state {
+            Ok(state) if state == on => {}
+            Ok(_) => {
+                let chord = Chord::new(Keyboard::Key(match led {
+                    Led::Num => Key::NumLock,
+                    Led::Caps => Key::CapsLock,
+                    Led::Scroll => Key::ScrollLock,
+                }));
+                if let Err(e) = chord.key_down(&mut self.device, &self.options.modifiers) {
+                    warn(&format!("key down error: {}", e));
+                }
+                self.sleep(self.keyhold);
+                if let Err(e) = chord.key_up(&mut self.device, &self.options.modifiers) {
+                    warn(&format!("key up error: {}", e));
+                }
+                self.pause(self.keydelay);
+                self.wait_led(led, on);
+            }
+            Err(e) => warn(&format!("cannot read LED state: {}", e)),
+        }
+    }
+
+    /// Taps Left Ctrl for --wake-key, so that a compositor that drops the
+    /// first event of a new device drops that instead of a real key.
+    fn wake(&mut self) {
+        self.tap_for("leftctrl", Duration::ZERO);
+    }
+
+    /// Turns on the locks of --initial-locks, and returns those that were
+    /// off so they can be restored.
+    fn set_initial_locks(&mut self) -> Vec<(Led, bool)> {
+        let mut changed = Vec::new();
+        for led in self.options.initial_locks.clone() {
+            let state = self
+                .led_device(led)
+                .and_then(|path| evdev::led_on(&path, led));
+            match state {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.set_lock(led, true);
+                    changed.push((led, false));
+                }
+                Err(e) => warn(&format!("cannot read LED state: {}", e)),
+            }
+        }
+        changed
+    }
+
+    /// Waits out --focus-settle if releasing `keys` may have moved the focus.
+    fn settle_focus(&self, keys: &[Keyboard]) {
+        let [super_key, _, _, alt, _] = self.options.modifiers.keys();
+        if keys.contains(&Keyboard::Key(super_key)) || keys.contains(&Keyboard::Key(alt)) {
+            self.sleep(self.options.focus_settle);
+        }
+    }
+
+    /// Warns about an action that reached --warn-slow.
+    fn check_slow(&mut self, line_number: usize, line: &str, took: Duration) {
+        let Some(limit) = self.options.warn_slow else {
+            return;
+        };
+        if took < limit {
+            return;
+        }
+        let action: String = line.trim().chars().take(40).collect();
+        warn(&format!(
+            "line {}: took {}ms: {}",
+            line_number,
+            took.as_millis(),
+            action
+        ));
+        self.slow += 1;
+        if self.slowest.is_none_or(|(_, slowest)| took > slowest) {
+            self.slowest = Some((line_number, took));
+        }
+    }
+
+    /// When the next chord is due to be auto-released or the next `at`
+    /// action to run.
+    fn next_deadline(&self) -> Option<Instant> {
+        let scheduled = self.scheduled.first().map(|(deadline, _)| *deadline);
+        self.next_release().into_iter().chain(scheduled).min()
+    }
+
+    /// Runs the `at` actions whose time has come, in the order they are due.
+    fn run_scheduled(&mut self) {
+        let now = Instant::now();
+        while self
+            .scheduled
+            .first()
+            .is_some_and(|(deadline, _)| *deadline <= now)
+        {
+            let (_, action) = self.scheduled.remove(0);
+            self.execute(&action);
+        }
+    }
+
+    /// The action of an `ifenv` or `ifset` whose condition holds, or None when
+    /// it does not.
+    fn condition_met<'a>(&self, op: &str, text: &'a str) -> Result<Option<&'a str>, String> {
+        let missing = || format!("{} needs a variable and an action", op);
+        let (var, rest) = next_token(text)?.ok_or_else(missing)?;
+        let (wanted, rest) = if op == "ifenv" {
+            let (value, rest) = next_token(rest)?.ok_or_else(missing)?;
+            (Some(value), rest)
+        } else {
+            (None, rest)
+        };
+        let action = rest.trim();
+        if action.is_empty() {
+            return Err(missing());
+        }
+        let met = match (env::var_os(&var), wanted) {
+            (Some(value), Some(wanted)) => value == *wanted,
+            (value, None) => value.is_some(),
+            (None, Some(_)) => false,
+        };
+        Ok(met.then_some(action))
+    }
+
+    /// Warns about a passed deadline, or under --strict stops the script.
+    fn check_deadline(&mut self) {
+        let Some(deadline) = self.deadline else {
+            return warn("checkdeadline without a deadline");
+        };
+        let Some(over) = Instant::now().checked_duration_since(deadline) else {
+            return;
+        };
+        self.fail(format!("deadline passed {}ms ago", over.as_millis()));
+    }
+
+    /// Warns about a failed check, or under --strict stops the script.
+    fn fail(&mut self, message: String) {
+        if self.options.strict {
+            self.aborted = Some(message);
+        } else {
+            warn(&message);
+        }
+    }
+
+    /// Waits for and runs the `at` actions still pending at the end of input.
+    fn finish_scheduled(&mut self) {
+        while self.aborted.is_none()
+            && let Some((deadline, _)) = self.scheduled.first()
+        {
+            self.sleep(deadline.saturating_duration_since(Instant::now()));
+            self.release_expired();
+            self.run_scheduled();
+        }
+    }
+
+    /// When the next chord held by `keydown` is due to be auto-released.
+    fn next_release(&self) -> Option<Instant> {
+        self.held.iter().map(|(_, _, deadline)| *deadline).min()
+    }
+
+    /// Releases the `keydown` chords that have been held past --max-hold.
+    fn release_expired(&mut self) {
+        let now = Instant::now();
+        let (expired, held) = self
+            .held
+            .drain(..)
+            .partition(|(_, _, deadline)| *deadline <= now);
+        self.held = held;
+        for (field, chord, _) in expired {
+            warn(&format!("{} held past max-hold, releasing it", field));
+            self.release_down(&chord);
+            if let Err(e) = chord.key_up(&mut self.device, &self.options.modifiers) {
+                warn(&format!("key up error: {}", e));
+            }
+        }
+    }
+
+    /// Forgets the keys of `chord` as held by `keydown`.
+    fn release_down(&mut self, chord: &Chord) {
+        let keys = chord.keys(&self.options.modifiers);
+        self.down.retain(|key| !keys.contains(key));
+    }
+
+    fn pause(&mut self, delay: Delay) {
+        let delay = delay.sample(&mut self.rng);
+        self.sleep(delay);
+    }
+
+    /// Waits, except in a --compare dry run, where only the events matter.
+    fn sleep(&self, duration: Duration) {
+        if !self.dry_run {
+            thread::sleep(duration);
+        }
+    }
+
+    /// Warns under --strict when `chord` would hold conflicting modifiers.
+    fn check_conflicts(&self, field: &str, chord: &Chord) {
+        if !self.options.strict {
+            return;
+        }
+        for conflict in modifier_conflicts(&self.down, chord, &self.options.modifiers) {
+            warn(&format!("{} holds {}", field, conflict));
+        }
+    }
+
+    /// Presses a chord and leaves it held, as the `keydown` action does.
+    fn keydown(&mut self, field: &str) {
+        let chord = match self.chord(field) {
+            Ok(chord) => chord,
+            Err(e) => return warn(&e),
+        };
+        self.check_conflicts(field, &chord);
+        let chord = chord.without(&self.down, &self.options.modifiers);
+        if let Err(e) = chord.key_down(&mut self.device, &self.options.modifiers) {
+            warn(&format!("key down error: {}", e));
+            self.pause(self.keydelay);
+            return;
+        }
+        for key in chord.keys(&self.options.modifiers) {
+            if !self.down.contains(&key) {
+                self.down.push(key);
+            }
+        }
+        if let Some(hold) = self.options.max_hold {
+            self.held.retain(|(_, held, _)| held.key != chord.key);
+            self.held
+                .push((field.to_string(), chord, Instant::now() + hold));
+        }
+        self.pause(self.keydelay);
+    }
+
+    /// Releases a chord, as the `keyup` action does.
+    fn keyup(&mut self, field: &str) {
+        let chord = match self.chord(field) {
+            Ok(chord) => chord,
+            Err(e) => return warn(&e),
+        };
+        self.held.retain(|(_, held, _)| held.key != chord.key);
+        self.release_down(&chord);
+        if let Err(e) = chord.key_up(&mut self.device, &self.options.modifiers) {
+            warn(&format!("key up error: {}", e));
+        }
+        self.settle_focus(&chord.keys(&self.options.modifiers));
+        self.pause(self.keydelay);
+    }
+
+    /// Releases the keys the innermost `with` pressed and that are still
+    /// held, newest first. Returns false when no block is open.
+    fn end_block(&mut self) -> bool {
+        let Some(mut keys) = self.blocks.pop() else {
+            return false;
+        };
+        keys.retain(|key| self.down.contains(key));
+        self.down.retain(|key| !keys.contains(key));
+        self.held.retain(|(_, held, _)| !keys.contains(&held.key));
+        let result = keys
+            .iter()
+            .rev()
+            .try_for_each(|key| self.device.release(key))
+            .and_then(|()| self.device.synchronize());
+        if let Err(e) = result {
+            warn(&format!("key up error: {}", e));
+        }
+        self.settle_focus(&keys);
+        self.pause(self.keydelay);
+        true
+    }
+
+    /// Taps `key` `count` times inside a `with` block of those of `modifiers`
+    /// not already held, for the `select` action.
+    fn select(&mut self, modifiers: &[&str], key: &str, count: usize) {
+        let missing: Vec<&str> = modifiers
+            .iter()
+            .copied()
+            .filter(|name| {
+                let key = self.options.modifiers.by_name(name).map(Keyboard::Key);
+                !key.is_some_and(|key| self.down.contains(&key))
+            })
+            .collect();
+        if missing.is_empty() {
+            for _ in 0..count {
+                self.tap(key);
+            }
+            return;
+        }
+        let before = self.down.len();
+        self.keydown(&missing.join("+"));
+        self.blocks.push(self.down[before..].to_vec());
+        for _ in 0..count {
+            self.tap(key);
+        }
+        self.end_block();
+    }
+
+    /// Presses all of `keys` in order, keyhold apart, then releases them in the
+    /// same order, or the reverse one when `nest` is set.
+    fn roll(&mut self, keys: &[Keyboard], nest: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut events: Vec<(bool, &Keyboard)> = keys.iter().map(|key| (true, key)).collect();
+        if nest {
+            events.extend(keys.iter().rev().map(|key| (false, key)));
+        } else {
+            events.extend(keys.iter().map(|key| (false, key)));
+        }
+        for (i, (pressing, key)) in events.into_iter().enumerate() {
+            if i > 0 {
+                self.sleep(self.keyhold);
+            }
+            if pressing {
+                self.device.press(key)?;
+            } else {
+                self.device.release(key)?;
+            }
+            self.device.synchronize()?;
+        }
+        Ok(())
+    }
+
+    /// Taps `key` `count` times, starting a tap every `interval`.
+    fn burst(
+        &mut self,
+        name: &str,
+        key: Keyboard,
+        count: usize,
+        interval: Duration,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        for i in 0..count {
+            // Keeping to the schedule rather than sleeping a fixed time means
+            // slow writes shorten the gaps instead of piling up.
+            let due = start + interval * i as u32;
+            self.sleep(due.saturating_duration_since(Instant::now()));
+            self.device.press(&key)?;
+            self.device.synchronize()?;
+            self.device.release(&key)?;
+            self.device.synchronize()?;
+        }
+        if self.options.verbose && count > 1 {
+            let elapsed = start.elapsed().as_secs_f64();
+            inform(&format!(
+                "burst {}: {} taps in {:.1}ms, {:.0} taps/s",
+                name,
+                count,
+                elapsed * 1000.0,
+                (count - 1) as f64 / elapsed.max(f64::EPSILON)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Presses and releases one chord, as the `key` action does.
+    fn tap(&mut self, field: &str) {
+        self.tap_for(field, self.hold_for(field));
+    }
+
+    /// Presses and releases one chord, holding it for `hold`.
+    fn tap_for(&mut self, field: &str, hold: Duration) {
+        match self.chord(field) {
+            Ok(chord) => {
+                self.check_conflicts(field, &chord);
+                let chord = chord.without(&self.down, &self.options.modifiers);
+                if let Err(e) = chord.key_down(&mut self.device, &self.options.modifiers) {
+                    warn(&format!("key down error: {}", e));
+                }
+                self.sleep(hold);
+                if let Err(e) = chord.key_up(&mut self.device, &self.options.modifiers) {
+                    warn(&format!("key up error: {}", e));
+                }
+                self.settle_focus(&chord.keys(&self.options.modifiers));
+                self.pause(self.keydelay);
+            }
+            Err(e) => warn(&e),
+        }
+    }
+
+    /// How long the `key` action holds a chord: its base key's `--hold-map`
+    /// entry if there is one, keyhold otherwise.
+    fn hold_for(&self, chord_str: &str) -> Duration {
+        parse_chord(chord_str, &self.linux_keys, &self.options)
+            .ok()
+            .and_then(|chord| self.options.hold_map.get(&(chord.key.code() as u16)))
+            .copied()
+            .unwrap_or(self.keyhold)
+    }
+
+    /// The chord `type` uses for `ch`. Characters it cannot type are noted
+    /// for the summary at the end, unless the control character handling
+    /// says to drop them quietly.
+    fn char_chord(&mut self, ch: char) -> Option<Chord> {
+        if ch == '\n' {
+            match self.options.newline {
+                Newline::Enter => {}
+                Newline::ShiftEnter => {
+                    let mut chord = Chord::new(Keyboard::Key(Key::Enter));
+                    chord.shift = true;
+                    return Some(chord);
+                }
+                Newline::Skip => return None,
+            }
+        }
+
+        if let Some(chord) = char_to_chord(ch, self.options.layout, &self.linux_keys) {
+            return Some(chord);
+        }
+
+        if !ch.is_ascii_control() {
+            if self.options.unicode_method == UnicodeMethod::None
+                && self.options.char_hook.is_none()
+                && !self.dropped.contains(&ch)
+            {
+                self.dropped.push(ch);
+            }
+            return None;
+        }
+
+        if self.options.control_chars == ControlChars::Drop {
+            return None;
+        }
+        let chord = control_to_chord(ch, &self.linux_keys);
+        if chord.is_none() && self.options.control_chars == ControlChars::Warn {
+            warn(&format!("cannot type control character: {:?}", ch));
+        }
+        chord
+    }
+
+    fn type_text(&mut self, s: &str) {
+        self.dropped.clear();
+        let s = self
+            .options
+            .type_transform
+            .apply(self.options.normalize.apply(s));
+        let mut chars = s.chars().peekable();
+        let total = s.chars().count();
+        let (mut done, mut shown) = (0, None);
+
+        while let Some(ch) = chars.next() {
+            if self.options.progress {
+                show_progress(done, total, &mut shown);
+            }
+            let mut count = 1;
+            if self.options.coalesce_repeats {
+                while chars.next_if_eq(&ch).is_some() {
+                    count += 1;
+                }
+            }
+            done += count;
+
+            let Some(chord) = self.char_chord(ch) else {
+                if !ch.is_ascii_control()
+                    && let Some(hook) = self.options.char_hook.clone()
+                {
+                    self.type_from_hook(&hook, ch, count);
+                    continue;
+                }
+                if self.options.unicode_method == UnicodeMethod::AltNumpad && !ch.is_ascii_control()
+                {
+                    for _ in 0..count {
+                        if let Err(e) = self.type_alt_numpad(ch) {
+                            warn(&format!("type error: {}", e));
+                        }
+                    }
+                    self.typed += count;
+                }
+                continue;
+            };
+            self.typed += count;
+            let chord = chord.without(&self.down, &self.options.modifiers);
+
+            if count > 1 {
+                if let Err(e) = self.type_repeated(&chord, count, self.char_delay(ch)) {
+                    warn(&format!("type error: {}", e));
+                }
+                continue;
+            }
+
+            if let Err(e) = chord.key_down(&mut self.device, &self.options.modifiers) {
+                warn(&format!("type error: {}", e));
+                continue;
+            }
+            self.sleep(self.typehold);
+            if let Err(e) = chord.key_up(&mut self.device, &self.options.modifiers) {
+                warn(&format!("type error: {}", e));
+            }
+            self.pause(self.char_delay(ch));
+        }
+
+        if self.options.progress {
+            show_progress(total, total, &mut shown);
+            eprintln!();
+        }
+        if !self.dropped.is_empty() {
+            let dropped: Vec<String> = self.dropped.iter().map(char::to_string).collect();
+            warn(&format!(
+                "cannot type {} character(s): {}",
+                dropped.len(),
+                dropped.join(", ")
+            ));
+        }
+    }
+
+    /// Puts the delays and holds back to the built-in defaults, for `defaults`.
+    fn reset_timing(&mut self) {
+        let defaults = Options::default();
+        self.keydelay = defaults.keydelay;
+        self.keyhold = defaults.keyhold;
+        self.typedelay = defaults.effective_typedelay();
+        self.typehold = defaults.typehold;
+        self.worddelay = defaults.worddelay;
+        self.options.hold_map.clear();
+    }
+
+    /// The delay after typing `ch`: worddelay for whitespace, if set.
+    fn char_delay(&self, ch: char) -> Delay {
+        match self.worddelay {
+            Some(worddelay) if ch.is_whitespace() => worddelay,
+            _ => self.typedelay,
+        }
+    }
+
+    /// Types the text of --type-markup pieces and taps their chords.
+    fn type_markup(&mut self, pieces: Vec<Markup>) {
+        for piece in pieces {
+            match piece {
+                Markup::Text(text) if self.options.type_escapes => self.type_text(&unescape(&text)),
+                Markup::Text(text) => self.type_text(&text),
+                Markup::Chords(chords) => {
+                    for field in chords.split_whitespace() {
+                        self.tap(field);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Types what --char-hook prints for `ch`, `count` times. The output is
+    /// typed without the hook, so a hook cannot set off more of itself.
+    fn type_from_hook(&mut self, hook: &str, ch: char, count: usize) {
+        let output = process::Command::new("sh")
+            .args(["-c", hook, "dotool"])
+            .arg(format!("U+{:04X}", ch as u32))
+            .stdin(process::Stdio::null())
+            .output();
+        let text = match output {
+            Ok(output) if output.status.success() => {
+                let text = String::from_utf8_lossy(&output.stdout);
+                text.strip_suffix('\n').unwrap_or(&text).to_string()
+            }
+            Ok(output) => {
+                return warn(&format!("char-hook for {}: {}", ch, output.status));
+            }
+            Err(e) => return warn(&format!("cannot run char-hook: {}", e)),
+        };
+
+        let hook = self.options.char_hook.take();
+        let dropped = std::mem::take(&mut self.dropped);
+        for _ in 0..count {
+            self.type_text(&text);
+        }
+        self.options.char_hook = hook;
+        self.dropped = dropped;
+    }
+
+    /// Types `ch` as Alt plus its decimal code point on the keypad. An Alt
+    /// that keydown already holds is used as it is and left held.
+    fn type_alt_numpad(&mut self, ch: char) -> Result<(), Box<dyn std::error::Error>> {
+        let alt = Keyboard::Key(self.options.modifiers.alt.pick(Key::LeftAlt, Key::RightAlt));
+        let held = self.down.contains(&alt);
+        if !held {
+            self.device.press(&alt)?;
+            self.device.synchronize()?;
+        }
+        for digit in (ch as u32).to_string().chars() {
+            let chord = Chord::new(self.linux_keys[&format!("kp{}", digit)]);
+            chord.key_down(&mut self.device, &self.options.modifiers)?;
+            self.sleep(self.typehold);
+            chord.key_up(&mut self.device, &self.options.modifiers)?;
+            self.pause(self.typedelay);
+        }
+        if !held {
+            self.device.release(&alt)?;
+            self.device.synchronize()?;
+        }
+        Ok(())
+    }
+
+    /// Types `chord` `count` times with its modifiers held throughout, rather
+    /// than pressing and releasing them around every character.
+    fn type_repeated(
+        &mut self,
+        chord: &Chord,
+        count: usize,
+        delay: Delay,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        chord.press_modifiers(&mut self.device, &self.options.modifiers)?;
+        for i in 0..count {
+            self.device.press(&chord.key)?;
+            self.device.synchronize()?;
+            self.sleep(self.typehold);
+            self.device.release(&chord.key)?;
+            if i == count - 1 {
+                chord.release_modifiers(&mut self.device, &self.options.modifiers)?;
+            }
+            self.device.synchronize()?;
+            self.pause(delay);
+        }
+        Ok(())
+    }
+}
+
+/// The typedelay that, together with typehold, averages `cpm` characters per minute.
+fn typedelay_for_cpm(cpm: f64, typehold: Duration) -> Duration {
+    Duration::from_secs_f64(60.0 / cpm).saturating_sub(typehold)
+}
+
+fn parse_rate(flag: &str, value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!(
+            "invalid value for {}: {}{}",
+            flag,
+            value,
+            comma_hint(value)
+        )),
+    }
+}
+
+fn parse_millis(name: &str, value: &str) -> Result<Duration, String> {
+    delay::millis(value)
+        .ok_or_else(|| format!("invalid value for {}: {}{}", name, value, comma_hint(value)))
+}
+
+fn parse_delay(name: &str, value: &str) -> Result<Delay, String> {
+    Delay::parse(value)
+        .ok_or_else(|| format!("invalid value for {}: {}{}", name, value, comma_hint(value)))
+}
+
+/// Points out a decimal comma in a number that failed to parse, since only a
+/// dot is accepted.
+fn comma_hint(value: &str) -> String {
+    if value.contains(',') {
+        format!(
+            " (write decimals with a dot, as in {})",
+            value.trim().replace(',', ".")
+        )
+    } else {
+        String::new()
+    }
+}
+
+fn parse_on_off(value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected on or off: {}", value)),
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("invalid value for {}: {}", name, value)),
+    }
+}
+
+fn flag_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("missing value for {}", flag))
+}
+
+const DEVICE_NAME: &str = "dotool keyboard";
+
+/// How long to wait for the keyboard's event node before carrying on anyway.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(1);
+/// The settle time used when the event nodes can't be listed.
+const STARTUP_FALLBACK: Duration = Duration::from_millis(200);
+
+/// Advice for the usual first-run failure: /dev/uinput exists but this user
+/// may not write to it.
+fn permission_hint() -> &'static str {
+    match fs::OpenOptions::new().write(true).open("/dev/uinput") {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EACCES | libc::EPERM)) => {
+            "\n/dev/uinput is not writable by this user. Add a udev rule such as
+    KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0620\", OPTIONS+=\"static_node=uinput\"
+then add yourself to the input group (usermod -aG input $USER) and log in again."
+        }
+        _ => "",
+    }
+}
+
+fn try_create_keyboard(bus: Option<Bus>, minimal_caps: bool) -> Result<Device, String> {
+    let mut builder = uinput::default()
+        .map_err(|e| format!("Failed to initialize uinput: {}{}", e, permission_hint()))?
+        .name(DEVICE_NAME)
+        .map_err(|e| format!("Failed to set device name: {}", e))?;
+    if let Some(bus) = bus {
+        builder = builder.bus(bus.code());
+    }
+    builder = if minimal_caps {
+        // Only the keys dotool has names for, which are all it can press.
+        key_codes()
+            .values()
+            .try_fold(builder, |builder, (_, key)| builder.event(*key))
+    } else {
+        builder.event(uinput::event::Keyboard::All)
+    }
+    .map_err(|e| format!("Failed to set keyboard events: {}", e))?;
+    builder
+        .create()
+        .map_err(|e| format!("Failed to create keyboard device: {}", e))
+}
+
+/// Creates the virtual keyboard, retrying with exponential backoff because
+/// udev may not have applied the /dev/uinput permissions yet after boot.
+fn create_keyboard(retries: u32, bus: Option<Bus>, minimal_caps: bool) -> Result<Device, String> {
+    let mut backoff = Duration::from_millis(100);
+    let mut attempt = 0;
+    loop {
+        match try_create_keyboard(bus, minimal_caps) {
+            Ok(device) => return Ok(device),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn(&format!(
+                    "{}; retrying in {}ms ({}/{})",
+                    e,
+                    backoff.as_millis(),
+                    attempt,
+                    retries
+                ));
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_secs(5));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Waits for an event node for our keyboard that wasn't in `before`, so the
+/// first actions aren't lost while udev and the compositor pick the device
+/// up, and returns it. Sleeps for a fixed time instead when the nodes can't be
+/// listed.
+fn wait_for_keyboard(before: io::Result<Vec<PathBuf>>) -> Option<PathBuf> {
+    let Ok(before) = before else {
+        thread::sleep(STARTUP_FALLBACK);
+        return None;
+    };
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while Instant::now() < deadline {
+        match evdev::nodes_named(DEVICE_NAME) {
+            Ok(nodes) => match nodes.into_iter().find(|node| !before.contains(node)) {
+                Some(node) => return Some(node),
+                None => thread::sleep(Duration::from_millis(10)),
+            },
+            Err(_) => {
+                thread::sleep(STARTUP_FALLBACK);
+                return None;
+            }
+        }
+    }
+    warn(&format!(
+        "the keyboard's event node did not appear within {}ms",
+        STARTUP_TIMEOUT.as_millis()
+    ));
+    None
+}
+
+/// Checks that the kernel gave the keyboard at `node` every key of
+/// --list-keys. An older kernel leaves out the codes it does not know yet,
+/// such as the newer Japanese and Korean keys, and presses of those would
+/// quietly do nothing. Under --strict that stops dotool.
+fn check_keys(node: &Path, options: &Options) -> Result<(), String> {
+    let missing = match evdev::missing_keys(node, key_codes().keys().copied()) {
+        Ok(missing) => missing,
+        // Reading our own node needs the same access as waitled.
+        Err(e) => {
+            if options.verbose {
+                inform(&format!(
+                    "cannot check the keys of {}: {}",
+                    node.display(),
+                    e
+                ));
+            }
+            return Ok(());
+        }
+    };
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<&str> = missing
+        .iter()
+        .map(|code| key_codes()[code].0.as_str())
+        .collect();
+    let message = format!(
+        "this kernel did not give the keyboard {} key(s), which will do nothing: {}",
+        names.len(),
+        names.join(", ")
+    );
+    if options.strict {
+        return Err(message);
+    }
+    warn(&message);
+    Ok(())
+}
+
+fn run() -> Result<(), String> {
+    let version = env!("CARGO_PKG_VERSION");
+    let argv: Vec<String> = env::args().skip(1).collect();
+
+    let linux_keys = init_linux_keys();
+    let mut options = Options::default();
+
+    // Parse command line arguments. Settings are only collected here, so that
+    // the profiles can be applied first and flags given next to them win.
+    let mut args = argv.into_iter().peekable();
+    let mut profiles = Vec::new();
+    let mut settings = Vec::new();
+    let mut show_config = false;
+    let mut verify = false;
+    let mut calibrate = false;
+    let mut compare = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                return write_stdout(usage);
+            }
+            "--version" => {
+                let verbose = args.next_if(|next| next == "--verbose").is_some();
+                return write_stdout(|out| print_version(out, version, verbose));
+            }
+            "--keymap-hash" => {
+                return write_stdout(|out| writeln!(out, "{}", keymap_hash(&linux_keys)));
+            }
+            "--list-keys" => {
+                let pattern = args.next_if(|next| !next.starts_with("--"));
+                return write_stdout(|out| list_keys(out, &linux_keys, pattern.as_deref()));
+            }
+            "--profile" => profiles.push(flag_value(&mut args, &arg)?),
+            "--show-config" => show_config = true,
+            "--verify-balance" => verify = true,
+            "--calibrate" => calibrate = true,
+            "--compare" => {
+                let first = flag_value(&mut args, &arg)?;
+                let second = flag_value(&mut args, &arg)?;
+                compare = Some((PathBuf::from(first), PathBuf::from(second)));
+            }
+            _ => {
+                let setting = arg
+                    .strip_prefix("--")
+                    .and_then(|name| SETTINGS.iter().find(|(s, _)| *s == name));
+                match setting {
+                    Some((name, true)) => settings.push((*name, flag_value(&mut args, &arg)?)),
+                    Some((name, false)) => settings.push((*name, "true".to_string())),
+                    None => return Err(format!("unknown argument: {}", arg)),
+                }
+            }
+        }
+    }
+
+    for path in &profiles {
+        options.load_profile(path)?;
+    }
+    for (name, value) in &settings {
+        options.set(name, value)?;
+    }
+
+    // Chosen once here, so that every executor below shares it and a run
+    // with random delays can be repeated.
+    if options.seed.is_none() {
+        let seed = delay::seed_from_time();
+        options.clock_seed = Some(seed);
+        if options.verbose {
+            inform(&format!("seed {}", seed));
+        }
+    }
+
+    if show_config {
+        return write_stdout(|out| options.show(out));
+    }
+
+    // Before anything is run or recorded, so that a bad entry stops every mode
+    // instead of leaving it unprotected.
+    if options.safe_mode {
+        safe_blocklist(&options, &linux_keys)?;
+    }
+
+    if let Some((first, second)) = compare {
+        let differences = compare_scripts(&first, &second, &linux_keys, &options)?;
+        write_stdout(|out| {
+            for difference in &differences {
+                writeln!(out, "{}", difference)?;
+            }
+            Ok(())
+        })?;
+        return match differences.len() {
+            0 => Ok(()),
+            n => Err(format!("the scripts differ in {} event(s)", n)),
+        };
+    }
+
+    if verify {
+        let problems =
+            verify_balance(io::stdin().lock(), &linux_keys, &options).map_err(|e| e.to_string())?;
+        for problem in &problems {
+            inform(problem);
+        }
+        return match problems.len() {
+            0 => Ok(()),
+            n => Err(format!("{} unbalanced key(s)", n)),
+        };
+    }
+
+    if options.type_stdin && options.replay_raw {
+        return Err("--type-stdin and --replay-raw both read stdin".into());
+    }
+
+    if options.type_stdin && options.type_transform == TypeTransform::Reverse {
+        return Err(
+            "--type-transform reverse needs the whole text, which --type-stdin does not keep"
+                .into(),
+        );
+    }
+
+    if let Some(path) = options.use_device.clone() {
+        if options.action_timeout.is_some() {
+            warn("--action-timeout does not apply to --use-device");
+        }
+        if options.bus.is_some() {
+            warn("--bus does not apply to --use-device");
+        }
+        if options.minimal_caps {
+            warn("--minimal-caps does not apply to --use-device");
+        }
+        let device = EventWriter::open(&path)
+            .map_err(|e| format!("cannot use {}: {}", path.display(), e))?;
+        let mut executor = Executor::new(device, linux_keys, options)?;
+        if calibrate {
+            run_calibration(&mut executor);
+            return Ok(());
+        }
+        return read_actions(executor);
+    }
+
+    if options.key_retries > 0 && options.action_timeout.is_some() {
+        warn("--key-retries does not apply with --action-timeout");
+        options.key_retries = 0;
+    }
+
+    // Other dotool instances may have keyboards of the same name.
+    let before = evdev::nodes_named(DEVICE_NAME);
+    let keyboard = create_keyboard(options.create_retries, options.bus, options.minimal_caps)?;
+    if options.startup_delay
+        && let Some(node) = wait_for_keyboard(before)
+    {
+        check_keys(&node, &options)?;
+    }
+
+    if calibrate {
+        run_calibration(&mut Executor::new(keyboard, linux_keys, options)?);
+        return Ok(());
+    }
+
+    match options.action_timeout {
+        Some(timeout) => {
+            let device = TimeoutDevice::new(keyboard, timeout);
+            read_actions(Executor::new(device, linux_keys, options)?)
+        }
+        None => read_actions(Executor::new(keyboard, linux_keys, options)?),
+    }
+}
+
+/// The name `--list-keys` gives a key, the first in order when it has several.
+fn key_name(key: &Keyboard, linux_keys: &HashMap<String, Keyboard>) -> String {
+    linux_keys
+        .iter()
+        .filter(|(_, k)| *k == key)
+        .map(|(name, _)| name.as_str())
+        .min()
+        .map_or_else(|| format!("{:?}", key), str::to_string)
+}
+
+/// An event as --compare records it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Recorded {
+    Press(Keyboard),
+    Release(Keyboard),
+    Repeat(Keyboard),
+    Sync,
+}
+
+/// Stands in for the device under --compare, keeping every event with the
+/// script line that sent it.
+#[derive(Default)]
+struct Recorder {
+    line: usize,
+    events: Vec<(usize, Recorded)>,
+}
+
+impl KeySink for Recorder {
+    fn press(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        self.events.push((self.line, Recorded::Press(*key)));
+        Ok(())
+    }
+
+    fn release(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        self.events.push((self.line, Recorded::Release(*key)));
+        Ok(())
+    }
+
+    fn repeat(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+        self.events.push((self.line, Recorded::Repeat(*key)));
+        Ok(())
+    }
+
+    fn synchronize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.events.push((self.line, Recorded::Sync));
+        Ok(())
+    }
+}
+
+/// Runs the script at `path` against a `Recorder`, without waiting.
+fn record_script(
+    path: &Path,
+    linux_keys: &HashMap<String, Keyboard>,
+    options: &Options,
+) -> Result<Vec<(usize, Recorded)>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let mut executor = Executor::new(Recorder::default(), linux_keys.clone(), options.clone())?;
+    executor.dry_run = true;
+    // Decoded the way the actions read from stdin are, invalid bytes and all.
+    for (i, line) in bytes.split(|&byte| byte == b'\n').enumerate() {
+        let line = String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line));
+        executor.device.sink.line = i + 1;
+        executor.execute(&line);
+        executor.run_scheduled();
+    }
+    executor.finish_scheduled();
+    while executor.end_block() {}
+    Ok(std::mem::take(&mut executor.device.sink.events))
+}
+
+/// The events one script sends and the other does not, each with the line
+/// that sends it: `-` for the first script and `+` for the second.
+fn compare_scripts(
+    first: &Path,
+    second: &Path,
+    linux_keys: &HashMap<String, Keyboard>,
+    options: &Options,
+) -> Result<Vec<String>, String> {
+    let a = record_script(first, linux_keys, options)?;
+    let b = record_script(second, linux_keys, options)?;
+    let events = |recorded: &[(usize, Recorded)]| -> Vec<Recorded> {
+        recorded.iter().map(|(_, event)| *event).collect()
+    };
+    let describe = |(line, event): &(usize, Recorded)| {
+        let event = match event {
+            Recorded::Press(key) => format!("press {}", key_name(key, linux_keys)),
+            Recorded::Release(key) => format!("release {}", key_name(key, linux_keys)),
+            Recorded::Repeat(key) => format!("repeat {}", key_name(key, linux_keys)),
+            Recorded::Sync => "sync".to_string(),
+        };
+        format!("line {}: {}", line, event)
+    };
+    Ok(diff::diff(&events(&a), &events(&b))
+        .into_iter()
+        .map(|edit| match edit {
+            Edit::Removed(i) => format!("- {} {}", first.display(), describe(&a[i])),
+            Edit::Added(i) => format!("+ {} {}", second.display(), describe(&b[i])),
+        })
+        .collect())
+}
+
+/// The action a line comes down to, past any `at`, `ifset`, `ifenv` and
+/// `repeat-until` in front of it, or None when those are malformed.
+fn inner_action(line: &str) -> Option<&str> {
+    let mut action = line.trim_start();
+    loop {
+        let (op, rest) = action
+            .split_once(char::is_whitespace)
+            .unwrap_or((action, ""));
+        action = match op {
+            "at" => next_token(rest).ok()??.1,
+            "ifset" => next_token(rest).ok()??.1,
+            "ifenv" => next_token(next_token(rest).ok()??.1).ok()??.1,
+            "repeat-until" => Condition::parse(rest).ok()?.1,
+            _ => return Some(action),
+        }
+        .trim_start();
+    }
+}
+
+/// Checks a script, without running it, for keys that keydown or press leave
+/// held at the end and for keys that keyup or release let go of without
+/// having pressed them. Actions put off by at, or that only run under a
+/// condition, are counted as if they ran where they are written. key, seq,
+/// select and type release whatever they press, so they cannot unbalance it.
+fn verify_balance(
+    reader: impl BufRead,
+    linux_keys: &HashMap<String, Keyboard>,
+    options: &Options,
+) -> io::Result<Vec<String>> {
+    let name = |key: &Keyboard| key_name(key, linux_keys);
+
+    let mut problems = Vec::new();
+    let mut held: Vec<(Keyboard, usize)> = Vec::new();
+    let mut blocks: Vec<Vec<Keyboard>> = Vec::new();
+    for (i, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        let line = String::from_utf8_lossy(&line);
+        let mut parts = inner_action(&line).unwrap_or("").split_whitespace();
+        let op = parts.next();
+        if op == Some("end") {
+            match blocks.pop() {
+                Some(block) => held.retain(|(key, _)| !block.contains(key)),
+                None => problems.push(format!("line {}: end without with", i + 1)),
+            }
+            continue;
+        }
+        let before = held.len();
+        let (pressing, single) = match op {
+            Some("keydown" | "with") => (true, false),
+            Some("keyup") => (false, false),
+            Some("press") => (true, true),
+            Some("release") => (false, true),
+            _ => continue,
+        };
+
+        for field in parts {
+            let keys = if single {
+                lookup_key(field, linux_keys).into_iter().collect()
+            } else {
+                match parse_chord(field, linux_keys, options) {
+                    Ok(chord) => chord.keys(&options.modifiers),
+                    Err(_) => continue,
+                }
+            };
+            for key in keys {
+                let position = held.iter().position(|(k, _)| *k == key);
+                match (pressing, position) {
+                    (true, None) => held.push((key, i + 1)),
+                    (false, Some(position)) => {
+                        held.remove(position);
+                    }
+                    (false, None) => problems.push(format!(
+                        "line {}: {} is released without being pressed",
+                        i + 1,
+                        name(&key)
+                    )),
+                    (true, Some(_)) => {}
+                }
+            }
+        }
+        if op == Some("with") {
+            blocks.push(held[before..].iter().map(|(key, _)| *key).collect());
+        }
+    }
+
+    for (key, line) in held {
+        problems.push(format!(
+            "line {}: {} is pressed and never released",
+            line,
+            name(&key)
+        ));
+    }
+    Ok(problems)
+}
+
+/// The delays --calibrate tries, slowest first.
+const CALIBRATION_DELAYS: &[u64] = &[20, 10, 5, 2, 1, 0];
+
+/// Types the --calibrate test pattern once per delay, using the delay for
+/// both typedelay and typehold.
+fn calibrate<S: KeySink>(executor: &mut Executor<S>, delays: &[u64]) {
+    for &ms in delays {
+        let delay = Duration::from_millis(ms);
+        executor.typedelay = Delay::fixed(delay);
+        executor.worddelay = None;
+        executor.typehold = delay;
+        executor.type_text(&format!(
+            "{:>2}ms The quick brown fox jumps over the lazy dog 0123456789\n",
+            ms
+        ));
+    }
+}
+
+/// Gives the user time to focus a text field, then calibrates and says how to
+/// read the result.
+fn run_calibration<S: KeySink>(executor: &mut Executor<S>) {
+    inform("focus an empty text field; typing starts in 3s");
+    thread::sleep(Duration::from_secs(3));
+    calibrate(executor, CALIBRATION_DELAYS);
+    inform(
+        "pass the delay of the fastest line that arrived complete and in order \
+         as --typedelay MS --typehold MS, or add 1 or 2ms to it for headroom",
+    );
+}
+
+/// Runs stdin through the executor until EOF, as actions or, with
+/// --type-stdin, as text to type, or with --replay-raw, as events to replay.
+fn read_actions<S: KeySink>(mut executor: Executor<S>) -> Result<(), String> {
+    let start = Instant::now();
+    if executor.options.wake_key {
+        executor.wake();
+    }
+    let locks = executor.set_initial_locks();
+    if executor.options.type_stdin {
+        type_stream(&mut executor, io::stdin().lock()).map_err(|e| e.to_string())?;
+    } else if executor.options.replay_raw {
+        replay_events(&mut executor, io::stdin().lock()).map_err(|e| e.to_string())?;
+    } else {
+        execute_lines(&mut executor)?;
+    }
+    if executor.options.restore_locks {
+        for (led, on) in locks {
+            executor.set_lock(led, on);
+        }
+    }
+
+    if executor.options.stats {
+        let elapsed = start.elapsed().as_secs_f64();
+        let mut extra = String::new();
+        if executor.options.key_retries > 0 {
+            extra += &format!(", {} retries", executor.device.retried);
+        }
+        if executor.options.warn_slow.is_some() {
+            extra += &format!(", {} slow actions", executor.slow);
+            if let Some((line, took)) = executor.slowest {
+                extra += &format!(" (slowest line {}, {}ms)", line, took.as_millis());
+            }
+        }
+        inform(&format!(
+            "{} keys pressed, {} characters typed, {} warnings{} in {:.2}s ({:.0} events/s)",
+            executor.device.pressed,
+            executor.typed,
+            WARNINGS.load(Ordering::Relaxed),
+            extra,
+            elapsed,
+            executor.device.emitted as f64 / elapsed.max(f64::EPSILON)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Types everything `reader` gives as it arrives, so that --type-stdin never
+/// holds more than one read of the input.
+fn type_stream<S: KeySink>(executor: &mut Executor<S>, mut reader: impl Read) -> io::Result<()> {
+    let mut buf = vec![0; 8192];
+    // The bytes of a character split across reads, and the last character
+    // read, which waits in case the next read starts with the combining
+    // accent that goes on it or the \n of a \r\n.
+    let mut partial = Vec::new();
+    let mut last = None;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        partial.extend_from_slice(&buf[..n]);
+
+        let mut text: String = last.take().into_iter().collect();
+        let mut rest = &partial[..];
+        while !rest.is_empty() {
+            match str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    text.push_str(str::from_utf8(valid).unwrap());
+                    // No error length means the read ended inside a character.
+                    let Some(len) = e.error_len() else {
+                        rest = after;
+                        break;
+                    };
+                    warn("invalid UTF-8 replaced with U+FFFD");
+                    text.push(char::REPLACEMENT_CHARACTER);
+                    rest = &after[len..];
+                }
+            }
+        }
+        partial = rest.to_vec();
+        let mut text = text.replace("\r\n", "\n");
+
+        // A combining mark already has its letter before it.
+        last = text
+            .chars()
+            .next_back()
+            .filter(|ch| !('\u{300}'..='\u{36f}').contains(ch));
+        if last.is_some() {
+            text.pop();
+        }
+        executor.type_text(&text);
+    }
+
+    if !partial.is_empty() {
+        warn("input ends inside a UTF-8 character");
+    }
+    if let Some(ch) = last {
+        executor.type_text(&ch.to_string());
+    }
+    Ok(())
+}
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const SYN_DROPPED: u16 = 3;
+
+/// Reads one input_event from the bytes of a record, which the kernel writes
+/// in the machine's own layout and byte order.
+fn decode_event(record: &[u8; mem::size_of::<libc::input_event>()]) -> libc::input_event {
+    unsafe { ptr::read_unaligned(record.as_ptr() as *const libc::input_event) }
+}
+
+/// Sends the input_event records `reader` gives to the device one by one, each
+/// as long after the first as its timestamp says. Keys the records leave down
+/// are released at the end.
+fn replay_events<S: KeySink>(executor: &mut Executor<S>, mut reader: impl Read) -> io::Result<()> {
+    let mut record = [0; mem::size_of::<libc::input_event>()];
+    let mut first = None;
+    let mut down = Vec::new();
+    let start = Instant::now();
+    loop {
+        let mut filled = 0;
+        while filled < record.len() {
+            match reader.read(&mut record[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if filled < record.len() {
+            if filled > 0 {
+                warn("input ends inside an input_event record");
+            }
+            break;
+        }
+        let event = decode_event(&record);
+
+        let time = Duration::new(event.time.tv_sec.max(0) as u64, 0)
+            + Duration::from_micros(event.time.tv_usec.max(0) as u64);
+        let first = *first.get_or_insert(time);
+        // Timestamps that go backwards are sent straight away.
+        let due = start + time.saturating_sub(first);
+        executor.sleep(due.saturating_duration_since(Instant::now()));
+
+        // SYN_DROPPED marks where the reader fell behind, not an event.
+        if event.type_ == EV_SYN && event.code == SYN_DROPPED {
+            warn("the capture dropped events here; replaying what it has");
+            continue;
+        }
+        if event.type_ == EV_KEY {
+            down.retain(|code| *code != event.code);
+            if event.value != 0 {
+                down.push(event.code);
+            }
+        }
+        if let Err(e) = executor.device.raw(event.type_, event.code, event.value) {
+            warn(&format!("failed to send event: {}", e));
+        }
+    }
+
+    if !down.is_empty() {
+        warn(&format!(
+            "releasing {} key(s) still down at the end of the capture",
+            down.len()
+        ));
+        for code in down {
+            if let Err(e) = executor.device.raw(EV_KEY, code, 0) {
+                warn(&format!("failed to release key: {}", e));
+            }
+        }
+        if let Err(e) = executor.device.raw(EV_SYN, 0, 0) {
+            warn(&format!("failed to synchronize: {}", e));
+        }
+    }
+    Ok(())
+}
+
+type Line = io::Result<Option<Vec<u8>>>;
+
+/// Reads lines from `reader` on their own thread, at most `depth` ahead of the
+/// receiver. The thread blocks once that many are waiting, which leaves the
+/// rest in the pipe for the writer to block on. `None` marks EOF.
+fn read_lines(reader: impl Read + Send + 'static, depth: usize) -> mpsc::Receiver<Line> {
+    let (sender, lines) = mpsc::sync_channel(depth);
+    thread::spawn(move || {
+        let mut reader = io::BufReader::new(reader);
+        loop {
+            let mut buf = Vec::new();
+            let line = reader
+                .read_until(b'\n', &mut buf)
+                .map(|n| (n > 0).then_some(buf));
+            let done = !matches!(line, Ok(Some(_)));
+            if sender.send(line).is_err() || done {
+                break;
+            }
+        }
+    });
+    lines
+}
+
+/// Executes the actions read from stdin, one per line, until EOF.
+///
+/// Lines are read on their own thread so that --max-hold can release a key
+/// while dotool is waiting for the next line.
+fn execute_lines<S: KeySink>(executor: &mut Executor<S>) -> Result<(), String> {
+    let lines = read_lines(io::stdin(), executor.options.queue_depth);
+
+    let mut line_number = 0;
+    loop {
+        let line = match executor.next_deadline() {
+            Some(deadline) => {
+                match lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(line) => line,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        executor.release_expired();
+                        executor.run_scheduled();
+                        if executor.aborted.is_some() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match lines.recv() {
+                Ok(line) => line,
+                Err(_) => break,
+            },
+        };
+        let Some(mut buf) = line.map_err(|e| e.to_string())? else {
+            break;
+        };
+        line_number += 1;
+        if buf.ends_with(b"\n") {
+            buf.pop();
+            if buf.ends_with(b"\r") {
+                buf.pop();
+            }
+        }
+
+        // A stray invalid byte should cost one character, not the session.
+        let line = String::from_utf8_lossy(&buf);
+        if let Cow::Owned(_) = line {
+            warn(&format!(
+                "line {}: invalid UTF-8 replaced with U+FFFD",
+                line_number
+            ));
+        }
+        let started = Instant::now();
+        executor.execute(&line);
+        executor.check_slow(line_number, &line, started.elapsed());
+        executor.release_expired();
+        executor.run_scheduled();
+        if executor.aborted.is_some() {
+            break;
+        }
+    }
+    executor.finish_scheduled();
+
+    if executor.aborted.is_none() && !executor.blocks.is_empty() {
+        warn(&format!(
+            "{} with blocks have no end, releasing their keys",
+            executor.blocks.len()
+        ));
+    }
+    while executor.end_block() {}
+
+    match executor.aborted.take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum SinkEvent {
+        Press(Keyboard),
+        Release(Keyboard),
+        Repeat(Keyboard),
+        Sync,
+        Raw(u16, u16, i32),
+    }
+
+    /// Records events instead of emitting them, so tests can run without uinput.
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Vec<SinkEvent>,
+        disabled: Vec<Keyboard>,
+        /// How long every event takes, to simulate a slow device.
+        stall: Duration,
+        /// How many of the next presses fail.
+        failures: usize,
+    }
+
+    impl KeySink for RecordingSink {
+        fn press(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+            thread::sleep(self.stall);
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err("device busy".into());
+            }
+            self.events.push(SinkEvent::Press(*key));
+            Ok(())
+        }
+
+        fn release(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+            thread::sleep(self.stall);
+            self.events.push(SinkEvent::Release(*key));
+            Ok(())
+        }
+
+        fn repeat(&mut self, key: &Keyboard) -> Result<(), Box<dyn std::error::Error>> {
+            self.events.push(SinkEvent::Repeat(*key));
+            Ok(())
+        }
+
+        fn synchronize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+            thread::sleep(self.stall);
+            self.events.push(SinkEvent::Sync);
+            Ok(())
+        }
+
+        fn raw(
+            &mut self,
+            kind: u16,
+            code: u16,
+            value: i32,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            self.events.push(SinkEvent::Raw(kind, code, value));
+            Ok(())
+        }
+
+        fn supports(&self, key: &Keyboard) -> bool {
+            !self.disabled.contains(key)
+        }
+    }
+
+    use SinkEvent::{Press, Raw, Release, Repeat, Sync};
+
+    const SHIFT: Keyboard = Keyboard::Key(Key::LeftShift);
+    const CTRL: Keyboard = Keyboard::Key(Key::LeftControl);
+
+    fn executor() -> Executor<RecordingSink> {
+        executor_with(RecordingSink::default())
+    }
+
+    fn executor_with<S: KeySink>(sink: S) -> Executor<S> {
+        let mut executor = Executor::new(sink, init_linux_keys(), Options::default()).unwrap();
+        executor.keydelay = Delay::fixed(Duration::ZERO);
+        executor.keyhold = Duration::ZERO;
+        executor.typedelay = Delay::fixed(Duration::ZERO);
+        executor.typehold = Duration::ZERO;
+        executor
+    }
+
+    fn run_script(script: &str) -> Vec<SinkEvent> {
+        let mut executor = executor();
+        for line in script.lines() {
+            executor.execute(line);
+        }
+        executor.device.sink.events
+    }
+
+    fn key(k: Key) -> Keyboard {
+        Keyboard::Key(k)
+    }
+
+    #[test]
+    fn type_shifts_uppercase_characters() {
+        assert_eq!(
+            run_script("type Hi"),
+            vec![
+                Press(SHIFT),
+                Press(key(Key::H)),
+                Sync,
+                Release(key(Key::H)),
+                Release(SHIFT),
+                Sync,
+                Press(key(Key::I)),
+                Sync,
+                Release(key(Key::I)),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn key_presses_modifiers_before_and_releases_after() {
+        assert_eq!(
+            run_script("key ctrl+shift+a"),
+            vec![
+                Press(CTRL),
+                Press(SHIFT),
+                Press(key(Key::A)),
+                Sync,
+                Release(key(Key::A)),
+                Release(SHIFT),
+                Release(CTRL),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn keydown_and_keyup_are_split() {
+        assert_eq!(
+            run_script("keydown ctrl+c\nkeyup ctrl+c"),
+            vec![
+                Press(CTRL),
+                Press(key(Key::C)),
+                Sync,
+                Release(key(Key::C)),
+                Release(CTRL),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn type_keeps_surrounding_spaces() {
+        let space = key(Key::Space);
+        assert_eq!(
+            run_script("  type  a "),
+            vec![
+                Press(space),
+                Sync,
+                Release(space),
+                Sync,
+                Press(key(Key::A)),
+                Sync,
+                Release(key(Key::A)),
+                Sync,
+                Press(space),
+                Sync,
+                Release(space),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn type_with_only_spaces_types_them_and_bare_type_does_nothing() {
+        let space = key(Key::Space);
+        assert_eq!(
+            run_script("type   "),
+            [Press(space), Sync, Release(space), Sync].repeat(2)
+        );
+        assert!(run_script("type").is_empty());
+        assert!(run_script("type ").is_empty());
+    }
+
+    #[test]
+    fn stats_count_presses_events_and_characters() {
+        let mut executor = executor();
+        executor.execute("key ctrl+a");
+        executor.execute("type hi");
+        assert_eq!(executor.device.pressed, 4);
+        assert_eq!(executor.device.emitted, 14);
+        assert_eq!(executor.typed, 2);
+    }
+
+    #[test]
+    fn alt_numpad_types_unmapped_characters_as_code_points() {
+        let mut executor = executor();
+        executor.options.unicode_method = UnicodeMethod::AltNumpad;
+        executor.execute("type \u{105}");
+        let alt = key(Key::LeftAlt);
+        let mut expected = vec![Press(alt), Sync];
+        for digit in [KeyPad::_2, KeyPad::_6, KeyPad::_1] {
+            let digit = Keyboard::KeyPad(digit);
+            expected.extend([Press(digit), Sync, Release(digit), Sync]);
+        }
+        expected.extend([Release(alt), Sync]);
+        assert_eq!(executor.device.sink.events, expected);
+
+        // An Alt the script holds stays held until its own keyup.
+        let mut held = executor_with(RecordingSink::default());
+        held.options.unicode_method = UnicodeMethod::AltNumpad;
+        held.execute("keydown alt");
+        held.execute("type \u{105}");
+        held.execute("keyup alt");
+        assert_eq!(held.device.sink.events, expected);
+    }
+
+    #[test]
+    fn modifier_sides_are_configurable() {
+        let mut executor = executor();
+        executor.options.set("shift-key", "right").unwrap();
+        executor.options.set("super-key", "right").unwrap();
+        executor.execute("key super+A");
+        let (meta, shift) = (key(Key::RightMeta), key(Key::RightShift));
+        assert_eq!(
+            executor.device.sink.events,
+            vec![
+                Press(meta),
+                Press(shift),
+                Press(key(Key::A)),
+                Sync,
+                Release(key(Key::A)),
+                Release(shift),
+                Release(meta),
+                Sync,
+            ]
+        );
+        assert!(executor.options.set("ctrl-key", "middle").is_err());
+    }
+
+    #[test]
+    fn keydown_modifiers_accumulate_across_lines() {
+        assert_eq!(
+            run_script("keydown ctrl\nkeydown shift\nkey a\nkey shift+b\nkeyup shift\nkeyup ctrl"),
+            vec![
+                Press(CTRL),
+                Sync,
+                Press(SHIFT),
+                Sync,
+                Press(key(Key::A)),
+                Sync,
+                Release(key(Key::A)),
+                Sync,
+                Press(key(Key::B)),
+                Sync,
+                Release(key(Key::B)),
+                Sync,
+                Release(SHIFT),
+                Sync,
+                Release(CTRL),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_sends_the_literal_value() {
+        let a = key(Key::A);
+        assert_eq!(
+            run_script("raw a 1\nraw a 2\nraw a 0\nraw a 3\nraw a"),
+            vec![Press(a), Sync, Repeat(a), Sync, Release(a), Sync]
+        );
+    }
+
+    #[test]
+    fn decimal_commas_get_a_hint() {
+        let err = parse_millis("keydelay", "0,5").unwrap_err();
+        assert!(err.ends_with("(write decimals with a dot, as in 0.5)"));
+        assert!(!parse_millis("keydelay", "x").unwrap_err().contains("dot"));
+
+        // What the hint suggests has to mean what it says.
+        assert_eq!(
+            parse_millis("keydelay", "0.5"),
+            Ok(Duration::from_micros(500))
+        );
+        assert_eq!(
+            Delay::parse("0.5").map(|d| d.sample(&mut Rng::new(1))),
+            Some(Duration::from_micros(500))
+        );
+        for bad in ["-5", "nan", "inf"] {
+            assert!(parse_millis("keydelay", bad).is_err(), "{}", bad);
+        }
+        let mut options = Options::default();
+        options.set("keyhold", "0.5").unwrap();
+        options.set("keydelay", "0.25-0.5").unwrap();
+        let mut shown = Vec::new();
+        options.show(&mut shown).unwrap();
+        let shown = String::from_utf8(shown).unwrap();
+        assert!(shown.contains("keyhold = 0.5\n"));
+        assert!(shown.contains("keydelay = \"0.25-0.5\"\n"));
+    }
+
+    #[test]
+    fn compare_reports_the_events_only_one_script_sends() {
+        assert_eq!(
+            diff::diff(&[1, 2, 3, 4], &[1, 3, 5, 4]),
+            vec![Edit::Removed(1), Edit::Added(2)]
+        );
+        let dir = env::temp_dir();
+        let first = dir.join(format!("dotool-compare-{}-a", process::id()));
+        let second = dir.join(format!("dotool-compare-{}-b", process::id()));
+        fs::write(&first, b"keydelay 1000\ntype ab\ntype \xff\n").unwrap();
+        fs::write(&second, b"key a\r\nkey shift+b\ntype \xff\n").unwrap();
+        let differences =
+            compare_scripts(&first, &second, &init_linux_keys(), &Options::default()).unwrap();
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+        let second = second.display();
+        assert_eq!(
+            differences,
+            vec![
+                format!("+ {} line 2: press leftshift", second),
+                format!("+ {} line 2: release leftshift", second),
+            ]
+        );
+    }
+
+    #[test]
+    fn verify_balance_reports_stuck_and_stray_keys() {
+        let script = b"keydown ctrl+a\nkeyup a\nkey b\nkeyup shift\npress c\nrelease c\n";
+        let problems =
+            verify_balance(&script[..], &init_linux_keys(), &Options::default()).unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                "line 4: leftshift is released without being pressed",
+                "line 1: leftctrl is pressed and never released",
+            ]
+        );
+
+        let script = concat!(
+            "ifset HOME keydown a\n",
+            "ifenv CI \"a b\" keyup x\n",
+            "repeat-until nofile \"/tmp/a b\" keydown y\n",
+            "at 10 ifset HOME keydown z\n",
+            "repeat-until file /tmp/done keyup y\n",
+            "seq copy\n",
+            "select word left 2\n",
+        );
+        let problems =
+            verify_balance(script.as_bytes(), &init_linux_keys(), &Options::default()).unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                "line 2: x is released without being pressed",
+                "line 1: a is pressed and never released",
+                "line 4: z is pressed and never released",
+            ]
+        );
+    }
+
+    #[test]
+    fn with_blocks_release_their_own_keys_at_end() {
+        let events = run_script("with shift\nwith ctrl\nkey a\nend\nkey b\nend");
+        let (a, b) = (key(Key::A), key(Key::B));
+        assert_eq!(
+            events,
+            vec![
+                Press(SHIFT),
+                Sync,
+                Press(CTRL),
+                Sync,
+                Press(a),
+                Sync,
+                Release(a),
+                Sync,
+                Release(CTRL),
+                Sync,
+                Press(b),
+                Sync,
+                Release(b),
+                Sync,
+                Release(SHIFT),
+                Sync,
+            ]
+        );
+        let script = b"with ctrl\nwith alt\nend\n";
+        let problems =
+            verify_balance(&script[..], &init_linux_keys(), &Options::default()).unwrap();
+        assert_eq!(
+            problems,
+            vec!["line 1: leftctrl is pressed and never released"]
+        );
+    }
+
+    /// A reader that hands out one byte at a time.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn type_stream_joins_characters_split_across_reads() {
+        let mut streamed = executor();
+        streamed.options.layout = Layout::Fr;
+        type_stream(&mut streamed, Trickle("e\u{301}\r\nà".as_bytes())).unwrap();
+        let mut expected = executor();
+        expected.options.layout = Layout::Fr;
+        expected.execute("type é");
+        expected.execute("type \n");
+        expected.execute("type à");
+        assert_eq!(streamed.device.sink.events, expected.device.sink.events);
+    }
+
+    fn input_event(usec: i64, kind: u16, code: u16, value: i32) -> Vec<u8> {
+        let event = libc::input_event {
+            time: libc::timeval {
+                tv_sec: 1_700_000_000 + usec / 1_000_000,
+                tv_usec: usec % 1_000_000,
+            },
+            type_: kind,
+            code,
+            value,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &event as *const libc::input_event as *const u8,
+                mem::size_of::<libc::input_event>(),
+            )
+        };
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn replay_events_keeps_timing_and_releases_what_is_left_down() {
+        let a = Key::A.code() as u16;
+        let b = Key::B.code() as u16;
+        let mut capture = Vec::new();
+        capture.extend(input_event(999_000, 4, 4, 30));
+        capture.extend(input_event(999_000, EV_KEY, a, 1));
+        capture.extend(input_event(999_000, EV_SYN, 0, 0));
+        capture.extend(input_event(1_009_000, EV_KEY, a, 0));
+        capture.extend(input_event(1_009_000, EV_KEY, b, 1));
+        capture.extend(input_event(1_009_000, EV_SYN, 0, 0));
+
+        let mut replayed = executor();
+        let start = Instant::now();
+        replay_events(&mut replayed, Trickle(&capture)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert_eq!(
+            replayed.device.sink.events,
+            vec![
+                Raw(4, 4, 30),
+                Raw(EV_KEY, a, 1),
+                Raw(EV_SYN, 0, 0),
+                Raw(EV_KEY, a, 0),
+                Raw(EV_KEY, b, 1),
+                Raw(EV_SYN, 0, 0),
+                Raw(EV_KEY, b, 0),
+                Raw(EV_SYN, 0, 0),
+            ]
+        );
+        assert_eq!(replayed.device.pressed, 2);
+    }
+
+    /// An endless script, one line per read, counting the lines read.
+    struct Flood(Arc<AtomicUsize>);
+
+    impl Read for Flood {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.fetch_add(1, Ordering::SeqCst);
+            let line = format!("key {}\n", n % 10);
+            buf[..line.len()].copy_from_slice(line.as_bytes());
+            Ok(line.len())
+        }
+    }
+
+    #[test]
+    fn read_lines_stops_reading_when_the_queue_is_full() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let lines = read_lines(Flood(read.clone()), 4);
+        // Wait for the reader to stop, however slowly it gets going.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut last = 0;
+        loop {
+            thread::sleep(Duration::from_millis(20));
+            let count = read.load(Ordering::SeqCst);
+            if (count > 0 && count == last) || Instant::now() >= deadline {
+                break;
+            }
+            last = count;
+        }
+        // At most the queue, plus the line blocked on going into it.
+        assert!((1..=5).contains(&last), "read {} lines", last);
+        for n in 0..20 {
+            let line = lines.recv().unwrap().unwrap().unwrap();
+            assert_eq!(line, format!("key {}\n", n % 10).into_bytes());
+        }
+    }
+
+    #[test]
+    fn keymap_hash_tracks_the_key_table() {
+        let keys = init_linux_keys();
+        let hash = keymap_hash(&keys);
+        assert_eq!(hash.len(), 16);
+        assert_eq!(keymap_hash(&keys.clone()), hash);
+        let mut fewer = keys.clone();
+        fewer.remove("esc");
+        assert_ne!(keymap_hash(&fewer), hash);
+
+        let mut executor = executor();
+        executor.options.strict = true;
+        executor.execute(&format!("requirekeymap {}", hash.to_uppercase()));
+        assert_eq!(executor.aborted, None);
+        executor.execute("requirekeymap 0000000000000000");
+        assert!(
+            executor
+                .aborted
+                .unwrap()
+                .starts_with("script needs key table")
+        );
+    }
+
+    #[test]
+    fn ifenv_and_ifset_run_their_action_when_the_condition_holds() {
+        let path = env::var("PATH").unwrap();
+        let mut executor = executor();
+        executor.execute("ifset PATH key a");
+        executor.execute("ifset DOTOOL_TEST_UNSET key b");
+        executor.execute(&format!("ifenv PATH \"{}\" key c", path));
+        executor.execute("ifenv PATH /nowhere key d");
+        executor.execute("ifenv DOTOOL_TEST_UNSET \"\" key e");
+        executor.execute("ifset PATH");
+        executor.execute("ifenv PATH");
+        assert_eq!(executor.device.sink.events, run_script("key a c"));
+    }
+
+    #[test]
+    fn nested_actions_that_are_bad_only_warn() {
+        let path = env::temp_dir().join(format!("dotool nested {}", process::id()));
+        let mut executor = executor();
+        executor.options.wait_timeout = Duration::from_millis(20);
+        executor.execute("ifset PATH bogus");
+        let value = env::var("PATH").unwrap();
+        executor.execute(&format!("ifenv PATH \"{}\" keydelay", value));
+        executor.execute("ifset PATH typedelay");
+        executor.execute(&format!("repeat-until file \"{}\" kye a", path.display()));
+        executor.execute("ifset PATH key a");
+        assert_eq!(executor.device.sink.events, run_script("key a"));
+    }
+
+    #[test]
+    fn checkdeadline_stops_the_script_under_strict() {
+        let mut executor = executor();
+        executor.execute("checkdeadline");
+        executor.execute("deadline 1000");
+        executor.execute("checkdeadline");
+        assert_eq!(executor.aborted, None);
+
+        executor.execute("deadline 0");
+        executor.execute("checkdeadline");
+        assert_eq!(executor.aborted, None);
+
+        executor.options.strict = true;
+        executor.execute("checkdeadline");
+        assert!(executor.aborted.unwrap().starts_with("deadline passed"));
+    }
+
+    #[test]
+    fn tokenize_keeps_quoted_spaces() {
+        assert_eq!(
+            tokenize("  a \"b  c\" d\"e f\"g \"\" ").unwrap(),
+            vec!["a", "b  c", "de fg", ""]
+        );
+        assert_eq!(
+            tokenize(r#""say \"hi\"" "back\\slash" "a\b""#).unwrap(),
+            vec![r#"say "hi""#, r"back\slash", r"a\b"]
+        );
+        assert!(tokenize("a \"b c").is_err());
+        assert_eq!(tokenize("").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn repeat_until_stops_once_the_condition_holds() {
+        assert_eq!(
+            Condition::parse(r#" nofile "/tmp/a b" key enter"#).unwrap(),
+            (Condition::File("/tmp/a b".into(), false), " key enter")
+        );
+        assert_eq!(
+            Condition::parse("led caps on type x").unwrap(),
+            (Condition::Led(Led::Caps, true), " type x")
+        );
+        assert!(Condition::parse("window Foo key a").is_err());
+        assert!(Condition::parse("file").is_err());
+
+        let path = env::temp_dir().join(format!("dotool repeat-until {}", process::id()));
+        fs::write(&path, "").unwrap();
+        let mut executor = executor();
+        executor.execute(&format!("repeat-until file \"{}\" key a", path.display()));
+        assert!(executor.device.sink.events.is_empty());
+
+        // A condition that never holds runs the action until wait-timeout.
+        executor.options.wait_timeout = Duration::from_millis(50);
+        executor.execute(&format!("repeat-until nofile \"{}\" key a", path.display()));
+        fs::remove_file(&path).unwrap();
+        let events = &executor.device.sink.events;
+        assert!(events.len() >= 8);
+        assert_eq!(events[..], run_script("key a").repeat(events.len() / 4)[..]);
+    }
+
+    #[test]
+    fn typefile_takes_a_quoted_path() {
+        let path = env::temp_dir().join(format!("dotool test {} ", process::id()));
+        fs::write(&path, "ab").unwrap();
+        let mut executor = executor();
+        executor.execute(&format!("typefile \"{}\"", path.display()));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(executor.device.sink.events, run_script("key a b"));
+    }
+
+    #[test]
+    fn safe_mode_drops_blocked_presses() {
+        let mut options = Options {
+            safe_mode: true,
+            ..Options::default()
+        };
+        let mut executor =
+            Executor::new(RecordingSink::default(), init_linux_keys(), options.clone()).unwrap();
+        executor.execute("key ctrl+alt+f2");
+        executor.execute("keydown ctrl");
+        executor.execute("key shift+alt+f3");
+        executor.execute("keyup ctrl");
+        executor.execute("press sysrq");
+        executor.execute("key ctrl+f2");
+
+        let (ctrl, alt, shift) = (CTRL, key(Key::LeftAlt), SHIFT);
+        let f2 = key(Key::F2);
+        let mut expected = vec![
+            Press(ctrl),
+            Press(alt),
+            Sync,
+            Release(f2),
+            Release(alt),
+            Release(ctrl),
+            Sync,
+        ];
+        expected.extend([Press(ctrl), Sync]);
+        expected.extend([Press(alt), Press(shift), Sync]);
+        expected.extend([Release(key(Key::F3)), Release(shift), Release(alt), Sync]);
+        expected.extend([Release(ctrl), Sync]);
+        expected.extend(run_script("key ctrl+f2"));
+        assert_eq!(executor.device.sink.events, expected);
+
+        options.set("safe-block", "ctrl+f2").unwrap();
+        let keys = init_linux_keys();
+        assert_eq!(safe_blocklist(&options, &keys).unwrap().len(), 1);
+        options.set("safe-block", "default,ctrl+alt+kpdot").unwrap();
+        assert_eq!(
+            safe_blocklist(&options, &keys).unwrap().len(),
+            SAFE_BLOCK_DEFAULT.len() + 1
+        );
+        options.set("safe-block", "ctrl+nope").unwrap();
+        assert!(safe_blocklist(&options, &keys).is_err());
+        assert!(Executor::new(RecordingSink::default(), keys, options).is_err());
+    }
+
+    #[test]
+    fn indentation_before_type_is_not_typed() {
+        let space = key(Key::Space);
+        let a = key(Key::A);
+        assert_eq!(
+            run_script("\t  type  a"),
+            vec![
+                Press(space),
+                Sync,
+                Release(space),
+                Sync,
+                Press(a),
+                Sync,
+                Release(a),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn char_hook_types_what_the_command_prints() {
+        let mut executor = executor();
+        executor.options.char_hook = Some("printf 'E(%s)' \"$1\"".to_string());
+        executor.execute("type a€");
+        assert_eq!(executor.device.sink.events, run_script("type aE(U+20AC)"));
+        assert!(executor.dropped.is_empty());
+    }
+
+    #[test]
+    fn type_collects_the_characters_it_drops() {
+        let mut executor = executor();
+        executor.execute("type €a™€");
+        assert_eq!(executor.dropped, vec!['€', '™']);
+        assert_eq!(executor.device.sink.events, run_script("type a"));
+        executor.execute("type b");
+        assert!(executor.dropped.is_empty());
+    }
+
+    #[test]
+    fn burst_taps_on_schedule() {
+        let mut executor = executor();
+        let started = Instant::now();
+        executor.execute("burst a 4 10");
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        let a = key(Key::A);
+        assert_eq!(
+            executor.device.sink.events,
+            [Press(a), Sync, Release(a), Sync].repeat(4)
+        );
+
+        for invalid in [
+            "burst a 0 10",
+            "burst a x 10",
+            "burst a 2",
+            "burst nope 2 1",
+            "burst a 2 soon",
+        ] {
+            executor.execute(invalid);
+        }
+        assert_eq!(executor.device.sink.events.len(), 16);
+    }
+
+    #[test]
+    fn select_holds_shift_across_the_arrows() {
+        let script = |lines: &[&str]| {
+            let mut executor = executor();
+            for line in lines {
+                executor.execute(line);
+            }
+            executor.device.sink.events
+        };
+        assert_eq!(
+            script(&["select right 3"]),
+            script(&["with shift", "key right right right", "end"])
+        );
+        assert_eq!(
+            script(&["select word left 2"]),
+            script(&["with ctrl+shift", "key left left", "end"])
+        );
+        assert_eq!(
+            script(&["select line right"]),
+            script(&["with shift", "key end", "end"])
+        );
+        // Shift already held stays held.
+        assert_eq!(
+            script(&["keydown shift", "select down 2"]),
+            script(&["keydown shift", "key down down"])
+        );
+        assert_eq!(
+            script(&["keydown shift", "select word right"]),
+            script(&["keydown shift", "with ctrl", "key right", "end"])
+        );
+        assert!(script(&["select sideways 2", "select right x", "select"]).is_empty());
+    }
+
+    #[test]
+    fn roll_overlaps_or_nests_its_keys() {
+        let (a, b) = (key(Key::A), key(Key::B));
+        assert_eq!(
+            run_script("roll a b"),
+            vec![
+                Press(a),
+                Sync,
+                Press(b),
+                Sync,
+                Release(a),
+                Sync,
+                Release(b),
+                Sync
+            ]
+        );
+        assert_eq!(
+            run_script("roll -nest a b"),
+            vec![
+                Press(a),
+                Sync,
+                Press(b),
+                Sync,
+                Release(b),
+                Sync,
+                Release(a),
+                Sync
+            ]
+        );
+    }
+
+    #[test]
+    fn sequence_skips_malformed_entries() {
+        let events = run_script("sequence a:0 b c:x ctrl+d:1");
+        assert_eq!(events, run_script("key a ctrl+d"));
+    }
+
+    #[test]
+    fn keydelay_changes_apply_to_the_keys_after_them() {
+        let mut executor = executor();
+        let started = Instant::now();
+        executor.execute("keydelay 40");
+        executor.execute("key a");
+        let after_a = started.elapsed();
+        executor.execute("keydelay 0");
+        executor.execute("key b");
+        let after_b = started.elapsed() - after_a;
+        assert!(after_a >= Duration::from_millis(40));
+        assert!(after_b < Duration::from_millis(40));
+        assert_eq!(executor.device.sink.events, run_script("key a b"));
+    }
+
+    #[test]
+    fn worddelay_applies_after_whitespace_only() {
+        let mut executor = executor();
+        executor.execute("typedelay 0");
+        executor.execute("typehold 0");
+        let started = Instant::now();
+        executor.execute("type abcdef");
+        assert!(started.elapsed() < Duration::from_millis(30));
+
+        executor.execute("worddelay 30");
+        let started = Instant::now();
+        executor.execute("type ab cd");
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert_eq!(
+            executor.char_delay('\n'),
+            Delay::fixed(Duration::from_millis(30))
+        );
+        assert_eq!(executor.char_delay('x'), Delay::fixed(Duration::ZERO));
+    }
+
+    #[test]
+    fn defaults_resets_timing_from_actions_and_options() {
+        let mut options = Options::default();
+        options.set("keyhold", "30").unwrap();
+        options.set("hold-map", "a=50").unwrap();
+        options.set("worddelay", "5-10").unwrap();
+        let mut executor =
+            Executor::new(RecordingSink::default(), init_linux_keys(), options).unwrap();
+        executor.execute("keydelay 1-9");
+        executor.execute("typedelay 7");
+        executor.execute("typehold 7");
+        executor.execute("defaults");
+
+        let defaults = Options::default();
+        assert_eq!(executor.keydelay, defaults.keydelay);
+        assert_eq!(executor.keyhold, defaults.keyhold);
+        assert_eq!(executor.typedelay, defaults.typedelay);
+        assert_eq!(executor.typehold, defaults.typehold);
+        assert_eq!(executor.worddelay, None);
+        assert_eq!(executor.hold_for("a"), defaults.keyhold);
+    }
+
+    #[test]
+    fn hold_map_finds_a_key_by_any_of_its_names() {
+        let mut options = Options::default();
+        options
+            .set("hold-map", "pageup=50,code:30=60,semicolon=70,equal=80")
+            .unwrap();
+        let executor = Executor::new(RecordingSink::default(), init_linux_keys(), options).unwrap();
+        let ms = Duration::from_millis;
+        assert_eq!(executor.hold_for("Prior"), ms(50));
+        assert_eq!(executor.hold_for("ctrl+A"), ms(60));
+        assert_eq!(executor.hold_for("colon"), ms(70));
+        assert_eq!(executor.hold_for("ctrl++"), ms(80));
+        assert_eq!(executor.hold_for("b"), executor.keyhold);
+        assert!(Options::default().set("hold-map", "nosuchkey=5").is_err());
+    }
+
+    #[test]
+    fn zero_holds_keep_press_and_release_apart() {
+        let mut executor = executor();
+        executor.execute("keyhold 5");
+        executor.execute("typehold 0");
+        assert_eq!(executor.keyhold, Duration::from_millis(5));
+        executor.execute("keyhold 0");
+        executor.execute("key a");
+        executor.execute("type a");
+        let a = key(Key::A);
+        assert_eq!(executor.keyhold, Duration::ZERO);
+        assert_eq!(
+            executor.device.sink.events,
+            [Press(a), Sync, Release(a), Sync].repeat(2)
+        );
+    }
+
+    #[test]
+    fn type_transform_changes_the_text_first() {
+        let mut upper = executor();
+        upper.options.type_transform = TypeTransform::Upper;
+        upper.execute("type ab");
+        let mut reverse = executor();
+        reverse.options.type_transform = TypeTransform::Reverse;
+        reverse.execute("type ab");
+        assert_eq!(upper.device.sink.events, run_script("type AB"));
+        assert_eq!(reverse.device.sink.events, run_script("type ba"));
+    }
+
+    #[test]
+    fn key_retries_resend_rejected_events() {
+        let mut executor = executor_with(RecordingSink {
+            failures: 2,
+            ..RecordingSink::default()
+        });
+        executor.device.retries = 2;
+        executor.execute("key a");
+        assert_eq!(executor.device.sink.events, run_script("key a"));
+        assert_eq!(executor.device.retried, 2);
+        assert_eq!(executor.device.pressed, 1);
+    }
+
+    #[test]
+    fn timeout_device_sends_its_whole_queue_before_exit() {
+        let script = "press a\npress b\nrelease b\nrelease a\nsync";
+        let slow = RecordingSink {
+            stall: Duration::from_millis(5),
+            ..RecordingSink::default()
+        };
+        // Every event misses the timeout, so they are all still queued when
+        // the script ends.
+        let mut executor = executor_with(TimeoutDevice::new(slow, Duration::from_millis(1)));
+        for line in script.lines() {
+            executor.execute(line);
+        }
+        let sink = executor.device.sink.finish().unwrap();
+        assert_eq!(sink.events, run_script(script));
+    }
+
+    #[test]
+    fn focus_settle_waits_after_alt_chords_only() {
+        let mut executor = executor();
+        executor.options.focus_settle = Duration::from_millis(30);
+        let started = Instant::now();
+        executor.execute("key ctrl+a shift+b");
+        assert!(started.elapsed() < Duration::from_millis(30));
+        executor.execute("keydown alt");
+        executor.execute("key tab");
+        executor.execute("keyup alt");
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn warn_slow_keeps_the_slowest_line() {
+        let mut executor = executor();
+        executor.options.warn_slow = Some(Duration::from_millis(10));
+        executor.check_slow(1, "type a", Duration::from_millis(12));
+        executor.check_slow(2, "type b", Duration::from_millis(9));
+        executor.check_slow(3, "type c", Duration::from_millis(30));
+        executor.check_slow(4, "type d", Duration::from_millis(20));
+        assert_eq!(executor.slow, 3);
+        assert_eq!(executor.slowest, Some((3, Duration::from_millis(30))));
+    }
+
+    #[test]
+    fn max_hold_releases_forgotten_keydowns_only() {
+        let mut executor = executor();
+        executor.options.max_hold = Some(Duration::ZERO);
+        executor.execute("keydown a");
+        executor.execute("keydown b");
+        executor.execute("keyup b");
+        executor.release_expired();
+        assert_eq!(
+            executor.device.sink.events,
+            vec![
+                Press(key(Key::A)),
+                Sync,
+                Press(key(Key::B)),
+                Sync,
+                Release(key(Key::B)),
+                Sync,
+                Release(key(Key::A)),
+                Sync,
+            ]
+        );
+        assert_eq!(executor.next_release(), None);
+    }
+
+    #[test]
+    fn bad_actions_only_warn_when_they_run_later() {
+        let mut executor = executor();
+        executor.execute("keydown shift");
+        executor.execute("at 0 kye a");
+        executor.execute("at 0 keydelay");
+        executor.execute("at 0 typedelay");
+        executor.run_scheduled();
+        executor.execute("keyup shift");
+        assert_eq!(
+            executor.device.sink.events,
+            run_script("keydown shift\nkeyup shift")
+        );
+    }
+
+    #[test]
+    fn at_runs_actions_later_in_the_order_they_are_due() {
+        let mut executor = executor();
+        executor.execute("at 20 key b");
+        executor.execute("at 0 key a");
+        executor.execute("key c");
+        executor.run_scheduled();
+        assert_eq!(executor.device.sink.events, run_script("key c\nkey a"));
+        executor.finish_scheduled();
+        assert_eq!(executor.device.sink.events, run_script("key c a b"));
+        assert_eq!(executor.next_deadline(), None);
+    }
+
+    #[test]
+    fn no_auto_shift_treats_uppercase_as_base_key() {
+        let keys = init_linux_keys();
+        let options = Options::default();
+        let no_shift = Options {
+            auto_shift: false,
+            ..Options::default()
+        };
+        assert!(parse_chord("A", &keys, &options).unwrap().shift);
+        assert!(!parse_chord("A", &keys, &no_shift).unwrap().shift);
+        assert!(parse_chord("shift+A", &keys, &no_shift).unwrap().shift);
+    }
+
+    #[test]
+    fn cpm_accounts_for_typehold() {
+        let typehold = Duration::from_millis(8);
+        assert_eq!(
+            typedelay_for_cpm(600.0, typehold),
+            Duration::from_millis(92)
+        );
+        assert_eq!(typedelay_for_cpm(60000.0, typehold), Duration::ZERO);
+    }
+
+    #[test]
+    fn delay_ranges_are_sampled_within_bounds_and_repeatable() {
+        let ms = Duration::from_millis;
+        assert_eq!(Delay::parse("5"), Some(Delay::fixed(ms(5))));
+        assert_eq!(Delay::parse("10-2"), None);
+        assert_eq!(Delay::parse("2-"), None);
+
+        let range = Delay::parse("2-10").unwrap();
+        let (mut a, mut b) = (Rng::new(7), Rng::new(7));
+        for _ in 0..100 {
+            let delay = range.sample(&mut a);
+            assert!((ms(2)..=ms(10)).contains(&delay));
+            assert_eq!(delay, range.sample(&mut b));
+        }
+    }
+
+    #[test]
+    fn type_follows_the_layout_shift_state() {
+        let mut executor = executor();
+        executor.options.layout = Layout::Fr;
+        executor.execute("type 1a");
+        assert_eq!(
+            executor.device.sink.events,
+            vec![
+                Press(SHIFT),
+                Press(key(Key::_1)),
+                Sync,
+                Release(key(Key::_1)),
+                Release(SHIFT),
+                Sync,
+                Press(key(Key::Q)),
+                Sync,
+                Release(key(Key::Q)),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn layout_action_switches_the_layout_type_uses() {
+        let mut executor = executor();
+        executor.execute("layout fr");
+        executor.execute("layout dvorak");
+        executor.execute("type a");
+        assert_eq!(executor.options.layout, Layout::Fr);
+        assert_eq!(
+            executor.device.sink.events,
+            vec![Press(key(Key::Q)), Sync, Release(key(Key::Q)), Sync]
+        );
+    }
+
+    #[test]
+    fn type_composes_combining_accents() {
+        let mut executor = executor();
+        executor.options.layout = Layout::Fr;
+        executor.execute("type e\u{301}");
+        assert_eq!(
+            executor.device.sink.events,
+            vec![Press(key(Key::_2)), Sync, Release(key(Key::_2)), Sync,]
+        );
+        assert_eq!(Normalization::Nfd.apply("é"), "e\u{301}");
+        assert_eq!(Normalization::None.apply("e\u{301}"), "e\u{301}");
+    }
+
+    #[test]
+    fn profile_keys_mirror_flags() {
+        let text = "# timing\nkeydelay = 20\nlayout = \"fr\"\nno-auto-shift = true\n";
+        let mut options = Options::default();
+        for (name, value) in profile::parse(text).unwrap() {
+            options.set(&name, &value).unwrap();
+        }
+        assert_eq!(options.keydelay, Delay::fixed(Duration::from_millis(20)));
+        assert_eq!(options.layout, Layout::Fr);
+        assert!(!options.auto_shift);
+        assert!(options.set("nosuchflag", "1").is_err());
+    }
+
+    #[test]
+    fn show_config_reads_back_as_a_profile() {
+        let mut options = Options::default();
+        options.set("char-hook", "say '$1'\r\x1b\"\\").unwrap();
+        options.set("led-device", "/tmp/a \"b\"\\c").unwrap();
+        options.set("use-device", "/dev/input/event\t9").unwrap();
+        options
+            .set("safe-block", "ctrl+alt+f1,ctrl+backslash")
+            .unwrap();
+        options.set("hold-map", "space=80").unwrap();
+        options.set("keydelay", "2-10").unwrap();
+        options.set("seq.save as", "ctrl+s").unwrap();
+        let mut shown = Vec::new();
+        options.show(&mut shown).unwrap();
+        let shown = String::from_utf8(shown).unwrap();
+
+        let mut read_back = Options::default();
+        for (name, value) in profile::parse(&shown).unwrap() {
+            read_back.set(&name, &value).unwrap();
+        }
+        assert_eq!(read_back.char_hook, options.char_hook);
+        assert_eq!(read_back.led_device, options.led_device);
+        let mut again = Vec::new();
+        read_back.show(&mut again).unwrap();
+        assert_eq!(String::from_utf8(again).unwrap(), shown);
+    }
+
+    #[test]
+    fn a_seed_from_the_clock_is_shown_but_not_saved() {
+        let options = Options {
+            clock_seed: Some(7),
+            ..Options::default()
+        };
+        let mut shown = Vec::new();
+        options.show(&mut shown).unwrap();
+        let shown = String::from_utf8(shown).unwrap();
+        assert!(shown.contains("# seed = 7"));
+        assert!(
+            profile::parse(&shown)
+                .unwrap()
+                .iter()
+                .all(|(name, _)| name != "seed")
+        );
+    }
+
+    #[test]
+    fn seq_runs_builtin_and_profile_sequences() {
+        let mut executor = executor();
+        for (name, value) in profile::parse("[seq]\nsaveas = \"ctrl+shift+s\"").unwrap() {
+            executor.options.set(&name, &value).unwrap();
+        }
+        executor.execute("seq copy SaveAs nosuchseq");
+        assert_eq!(
+            executor.device.sink.events,
+            vec![
+                Press(CTRL),
+                Press(key(Key::C)),
+                Sync,
+                Release(key(Key::C)),
+                Release(CTRL),
+                Sync,
+                Press(CTRL),
+                Press(SHIFT),
+                Press(key(Key::S)),
+                Sync,
+                Release(key(Key::S)),
+                Release(SHIFT),
+                Release(CTRL),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn x11_keysym_names_resolve() {
+        let keys = init_linux_keys();
+        let options = Options::default();
+        let chord = parse_chord("ctrl+Prior", &keys, &options).unwrap();
+        assert_eq!(chord.key, key(Key::PageUp));
+        assert!(!chord.shift);
+        assert_eq!(
+            parse_chord("KP_Add", &keys, &options).unwrap().key,
+            Keyboard::KeyPad(KeyPad::Plus)
+        );
+    }
+
+    #[test]
+    fn type_escapes_type_the_escaped_character() {
+        let mut executor = executor();
+        executor.options.type_escapes = true;
+        executor.execute(r"type a\\b\;\");
+        assert_eq!(executor.device.sink.events, run_script(r"type a\b;\"));
+    }
+
+    #[test]
+    fn strict_finds_modifier_pairs_held_together() {
+        let keys = init_linux_keys();
+        let mut options = Options::default();
+        let chord = |s: &str, options: &Options| parse_chord(s, &keys, options).unwrap();
+        let down = [key(Key::RightShift)];
+        assert_eq!(
+            modifier_conflicts(&down, &chord("ctrl+shift+a", &options), &options.modifiers),
+            vec!["both Shift keys"]
+        );
+        assert!(
+            modifier_conflicts(&down, &chord("ctrl+a", &options), &options.modifiers).is_empty()
+        );
+        options.modifiers.alt = Side::Right;
+        assert_eq!(
+            modifier_conflicts(&[], &chord("altgr+alt+a", &options), &options.modifiers),
+            vec!["AltGr and Alt, which --alt-key right makes the same key"]
+        );
+    }
+
+    #[test]
+    fn calibrate_types_a_labelled_line_per_delay() {
+        let mut executor = executor();
+        calibrate(&mut executor, &[1, 0]);
+        let line = " 1ms The quick brown fox jumps over the lazy dog 0123456789\n";
+        assert_eq!(executor.typed, line.len() * 2);
+        assert!(executor.dropped.is_empty());
+        assert_eq!(executor.typehold, Duration::ZERO);
+    }
+
+    #[test]
+    fn bus_names_the_kernel_bus_types() {
+        let mut options = Options::default();
+        assert_eq!(options.bus, None);
+        options.set("bus", "bluetooth").unwrap();
+        assert_eq!(options.bus.map(Bus::code), Some(0x05));
+        assert!(options.set("bus", "pci").is_err());
+    }
+
+    #[test]
+    fn wake_taps_left_ctrl() {
+        let mut executor = executor();
+        executor.wake();
+        assert_eq!(
+            executor.device.sink.events,
+            vec![Press(CTRL), Sync, Release(CTRL), Sync]
+        );
+    }
+
+    #[test]
+    fn initial_locks_take_a_list_of_leds() {
+        let mut options = Options::default();
+        options.set("initial-locks", "num, caps").unwrap();
+        assert_eq!(options.initial_locks, vec![Led::Num, Led::Caps]);
+        options.set("initial-locks", "").unwrap();
+        assert!(options.initial_locks.is_empty());
+        assert!(options.set("initial-locks", "num,shift").is_err());
+    }
+
+    #[test]
+    fn type_markup_taps_the_chords_in_braces() {
+        let mut executor = executor();
+        executor.options.type_markup = true;
+        executor.execute("type hi{enter}{{x}{ctrl+a shift+b}");
+        assert_eq!(
+            executor.device.sink.events,
+            run_script("type hi\nkey enter\ntype {x}\nkey ctrl+a shift+b")
+        );
+        assert!(parse_markup("a{ctrl+c").is_err());
+    }
+
+    #[test]
+    fn coalesced_repeats_hold_shift_for_the_run() {
+        let mut executor = executor();
+        executor.options.coalesce_repeats = true;
+        executor.execute("type AAb");
+        let a = key(Key::A);
+        let b = key(Key::B);
+        assert_eq!(
+            executor.device.sink.events,
+            vec![
+                Press(SHIFT),
+                Press(a),
+                Sync,
+                Release(a),
+                Sync,
+                Press(a),
+                Sync,
+                Release(a),
+                Release(SHIFT),
+                Sync,
+                Press(b),
+                Sync,
+                Release(b),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn single_characters_merge_their_own_modifiers() {
+        let keys = init_linux_keys();
+        let options = Options::default();
+
+        let chord = parse_chord("alt+@", &keys, &options).unwrap();
+        assert_eq!(chord.key, key(Key::_2));
+        assert!(chord.alt && chord.shift);
+
+        let chord = parse_chord("ctrl++", &keys, &options).unwrap();
+        assert_eq!(chord.key, key(Key::Equal));
+        assert!(chord.ctrl && chord.shift);
+
+        let fr = Options {
+            layout: Layout::Fr,
+            ..Options::default()
+        };
+        let chord = parse_chord("ctrl+é", &keys, &fr).unwrap();
+        assert_eq!(chord.key, key(Key::_2));
+        assert!(chord.ctrl && !chord.shift);
+
+        assert!(parse_chord("ctrl+é", &keys, &options).is_err());
+    }
+
+    #[test]
+    fn key_codes_resolve_and_suggest_the_nearest() {
+        let keys = init_linux_keys();
+        assert_eq!(lookup_key("code:30", &keys), Some(key(Key::A)));
+        assert_eq!(lookup_key("CODE:0x1e", &keys), Some(key(Key::A)));
+        let chord = parse_chord("ctrl+code:46", &keys, &Options::default()).unwrap();
+        assert_eq!(chord.key, key(Key::C));
+        assert!(chord.ctrl);
+
+        assert_eq!(lookup_key("code:0", &keys), None);
+        assert_eq!(
+            unknown_key("code:0"),
+            "no key has code 0; the nearest is code:1 (esc)"
+        );
+        assert_eq!(
+            parse_chord("ctrl+code:0", &keys, &Options::default()).unwrap_err(),
+            "no key has code 0; the nearest is code:1 (esc)"
+        );
+        assert!(unknown_key("code:65535").contains("; the nearest is code:"));
+        assert_eq!(unknown_key("code:x"), "invalid key code: code:x");
+        assert_eq!(unknown_key("nope"), "impossible key for layout: nope");
+    }
+
+    #[test]
+    fn symbol_names_merge_their_own_modifiers() {
+        let keys = init_linux_keys();
+        let options = Options::default();
+        let fr = Options {
+            layout: Layout::Fr,
+            ..Options::default()
+        };
+
+        for chord in ["ctrl+:", "ctrl+colon", "ctrl+Colon", "ctrl+shift+colon"] {
+            let chord = parse_chord(chord, &keys, &options).unwrap();
+            assert_eq!(chord.key, key(Key::SemiColon));
+            assert!(chord.ctrl && chord.shift && !chord.altgr);
+        }
+
+        let chord = parse_chord("alt+question", &keys, &options).unwrap();
+        assert_eq!(chord.key, key(Key::Slash));
+        assert!(chord.alt && chord.shift);
+
+        let chord = parse_chord("super+braceleft", &keys, &options).unwrap();
+        assert_eq!(chord.key, key(Key::LeftBrace));
+        assert!(chord.super_key && chord.shift);
+
+        // The same symbols sit elsewhere on AZERTY, some of them on AltGr.
+        let chord = parse_chord("ctrl+colon", &keys, &fr).unwrap();
+        assert_eq!(chord.key, key(Key::Dot));
+        assert!(chord.ctrl && !chord.shift && !chord.altgr);
+
+        let chord = parse_chord("ctrl+at", &keys, &fr).unwrap();
+        assert_eq!(chord.key, key(Key::_0));
+        assert!(chord.ctrl && chord.altgr && !chord.shift);
+
+        let chord = parse_chord("shift+braceleft", &keys, &fr).unwrap();
+        assert_eq!(chord.key, key(Key::_4));
+        assert!(chord.shift && chord.altgr);
+
+        assert!(parse_chord("ctrl+eurosign", &keys, &options).is_err());
+        assert!(parse_chord("ctrl+eurosign", &keys, &fr).unwrap().altgr);
+    }
+
+    #[test]
+    fn newlines_follow_type_newline_as() {
+        let mut shifting = executor();
+        shifting.options.newline = Newline::ShiftEnter;
+        shifting.type_text("\n");
+        let enter = key(Key::Enter);
+        assert_eq!(
+            shifting.device.sink.events,
+            vec![
+                Press(SHIFT),
+                Press(enter),
+                Sync,
+                Release(enter),
+                Release(SHIFT),
+                Sync
+            ]
+        );
+
+        let mut skipping = executor();
+        skipping.options.newline = Newline::Skip;
+        skipping.type_text("\n");
+        assert!(skipping.device.sink.events.is_empty());
+    }
+
+    #[test]
+    fn press_and_release_leave_framing_to_sync() {
+        assert_eq!(
+            run_script("press leftshift a\nrelease a leftshift\nsync"),
+            vec![
+                Press(SHIFT),
+                Press(key(Key::A)),
+                Release(key(Key::A)),
+                Release(SHIFT),
+                Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn chords_with_unsupported_keys_are_rejected_whole() {
+        let mut executor = executor();
+        executor.device.sink.disabled.push(key(Key::LeftMeta));
+        executor.execute("key super+a");
+        assert!(executor.device.sink.events.is_empty());
+        assert!(executor.chord("super+a").is_err());
+        assert!(executor.chord("ctrl+a").is_ok());
+    }
+
+    #[test]
+    fn type_maps_known_control_characters_and_drops_others() {
+        assert_eq!(
+            run_script("type \x1b\x0c"),
+            vec![Press(key(Key::Esc)), Sync, Release(key(Key::Esc)), Sync]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_emit_nothing() {
+        assert!(run_script("key nosuchkey").is_empty());
+    }
 }
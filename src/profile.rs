@@ -0,0 +1,157 @@
+//! A minimal TOML reader for `--profile` files.
+//!
+//! Only what a flat settings file needs is supported: `key = value` pairs,
+//! `[table]` headers, comments, and string, number and boolean values. Keys
+//! inside a table are returned as `table.key`. Values come back as text, to be
+//! parsed the same way as the command line flag they mirror.
+
+use std::fmt;
+
+pub fn parse(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut settings = Vec::new();
+    let mut table = String::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let error = |msg: &str| format!("line {}: {}", i + 1, msg);
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix('[') {
+            let name = strip_comment(rest)
+                .strip_suffix(']')
+                .ok_or_else(|| error("unterminated table header"))?
+                .trim();
+            if !is_bare_key(name) {
+                return Err(error(&format!("invalid table name: {}", name)));
+            }
+            table = name.to_string();
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected key = value"))?;
+        let key = unquote_key(key.trim()).ok_or_else(|| error("invalid key"))?;
+        let value = parse_value(value.trim()).map_err(|e| error(&e))?;
+
+        if table.is_empty() {
+            settings.push((key, value));
+        } else {
+            settings.push((format!("{}.{}", table, key), value));
+        }
+    }
+
+    Ok(settings)
+}
+
+/// `value` as a TOML basic string, escaped so that `parse` reads it back
+/// unchanged.
+pub fn quote(value: impl fmt::Display) -> String {
+    let mut out = String::from("\"");
+    for c in value.to_string().chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `key` as a TOML key: bare when it can be, quoted otherwise.
+pub fn quote_key(key: &str) -> String {
+    if is_bare_key(key) {
+        key.to_string()
+    } else {
+        quote(key)
+    }
+}
+
+fn is_bare_key(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn unquote_key(key: &str) -> Option<String> {
+    if is_bare_key(key) {
+        return Some(key.to_string());
+    }
+    match parse_value(key) {
+        Ok(s) if key.starts_with('"') || key.starts_with('\'') => Some(s),
+        _ => None,
+    }
+}
+
+fn strip_comment(s: &str) -> &str {
+    match s.find('#') {
+        Some(i) => s[..i].trim_end(),
+        None => s.trim_end(),
+    }
+}
+
+fn parse_value(value: &str) -> Result<String, String> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("unterminated string")?;
+        if !strip_comment(&rest[end + 1..]).is_empty() {
+            return Err("unexpected text after string".to_string());
+        }
+        return Ok(rest[..end].to_string());
+    }
+
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    if !strip_comment(&rest[i + 1..]).is_empty() {
+                        return Err("unexpected text after string".to_string());
+                    }
+                    return Ok(out);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => out.push('\n'),
+                    Some((_, 't')) => out.push('\t'),
+                    Some((_, 'r')) => out.push('\r'),
+                    Some((_, 'b')) => out.push('\u{8}'),
+                    Some((_, 'f')) => out.push('\u{c}'),
+                    Some((_, c @ ('u' | 'U'))) => {
+                        let digits = if c == 'u' { 4 } else { 8 };
+                        let hex: String = chars.by_ref().take(digits).map(|(_, c)| c).collect();
+                        let ch = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .filter(|_| {
+                                hex.len() == digits && hex.chars().all(|c| c.is_ascii_hexdigit())
+                            })
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("invalid escape: \\{}{}", c, hex))?;
+                        out.push(ch);
+                    }
+                    Some((_, '"')) => out.push('"'),
+                    Some((_, '\\')) => out.push('\\'),
+                    Some((_, c)) => return Err(format!("unsupported escape: \\{}", c)),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+
+    let value = strip_comment(value);
+    let is_number = value.replace('_', "").parse::<f64>().is_ok();
+    if value == "true" || value == "false" || is_number {
+        Ok(value.replace('_', ""))
+    } else {
+        Err(format!("unsupported value: {}", value))
+    }
+}